@@ -0,0 +1,466 @@
+use crate::history::{
+    blob_path, list_snapshots, prune, store_blob, write_manifest, BlobRef, Snapshot, SnapshotInfo,
+};
+use crate::prelude::*;
+use std::ffi::OsStr;
+use std::fs;
+use std::process::Command;
+
+/// gitの履歴から一覧に表示するコミットの数
+const GIT_LOG_LIMIT: usize = 200;
+
+/// 保存する1テーブルの内容
+pub struct TableChange<'a> {
+    pub table_name: &'a OsString,
+    pub bytes: &'a [u8],
+    /// 追加・削除・変更された行数
+    pub counts: (usize, usize, usize),
+}
+
+/// 保存した内容を履歴として残す方法
+/// マスタを書き換える前後に呼ばれ、書き換えに失敗した場合は履歴にも残さない
+pub trait Archive {
+    /// 設定で指定する名前
+    fn name(&self) -> &'static str;
+    /// マスタを書き換える前に呼ぶ。tablesはマスタのすべてのテーブル
+    fn before_write(
+        &mut self,
+        changes: &[TableChange],
+        tables: &[OsString],
+        info: &SnapshotInfo,
+    ) -> Result<()>;
+    /// マスタを書き換えた後に呼ぶ。writtenは書き換えに成功したか
+    fn after_write(
+        &mut self,
+        changes: &[TableChange],
+        info: &SnapshotInfo,
+        written: bool,
+    ) -> Result<()>;
+    /// スナップショットを新しい順に返す
+    fn snapshots(&self) -> Result<Vec<Snapshot>>;
+    /// スナップショット内のテーブルの内容を読めるファイルのパス
+    fn table_path(&self, snapshot: &Snapshot, table_name: &OsString) -> Result<PathBuf>;
+    /// 古いスナップショットを消せるか
+    fn can_prune(&self) -> bool {
+        false
+    }
+    /// スナップショットを消し、消した数を返す
+    fn prune(&mut self, snapshots: &[&Snapshot]) -> Result<usize> {
+        let _ = snapshots;
+        anyhow::bail!("the {} history can't be pruned", self.name())
+    }
+    /// スナップショットのテーブルをCSVファイルとしてフォルダに書き出す
+    fn export(&self, snapshot: &Snapshot, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        for table_name in snapshot.tables.iter() {
+            let content = get_bytes(&self.table_path(snapshot, table_name)?)
+                .with_context(|| format!("{}", table_name.to_string_lossy()))?;
+            save_to_file(content, dest.join(table_name))?;
+        }
+        Ok(())
+    }
+}
+
+/// 設定の[archive]に従って履歴の残し方を選ぶ
+pub fn from_config(
+    config: &Value,
+    master_dir: &Path,
+    archive_dir: &Path,
+) -> Result<Box<dyn Archive>> {
+    let backend = config
+        .get("archive")
+        .and_then(|a| a.get("backend"))
+        .and_then(Value::as_str)
+        .unwrap_or("folder");
+    match backend {
+        "folder" => Ok(Box::new(FolderArchive::new(master_dir, archive_dir))),
+        "git" => Ok(Box::new(GitArchive::new(master_dir, archive_dir)?)),
+        other => anyhow::bail!("archive.backend must be \"folder\" or \"git\": {}", other),
+    }
+}
+
+/// 保存のたびに履歴ディレクトリにスナップショットのフォルダを作る
+pub struct FolderArchive {
+    master_dir: PathBuf,
+    archive_dir: PathBuf,
+    /// 変更していないマスタファイルの、前回保存したときの状態。読み直さずにハッシュを使い回す
+    master_stamps: BTreeMap<OsString, FileStamp>,
+    /// 作成中のスナップショットのフォルダ
+    pending: Option<PathBuf>,
+}
+impl FolderArchive {
+    pub fn new(master_dir: &Path, archive_dir: &Path) -> FolderArchive {
+        FolderArchive {
+            master_dir: master_dir.to_path_buf(),
+            archive_dir: archive_dir.to_path_buf(),
+            master_stamps: BTreeMap::new(),
+            pending: None,
+        }
+    }
+    /// 変更していないテーブルのマスタファイルの内容をハッシュで保存する
+    /// 前回から変わっていなければ読み直さない。マスタファイルがなければNone
+    fn store_master_blob(&mut self, table_name: &OsString) -> Result<Option<BlobRef>> {
+        let path = self.master_dir.join(table_name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        if let Some(stamp) = self.master_stamps.get(table_name) {
+            let stored = stamp
+                .hash
+                .as_ref()
+                .filter(|hash| blob_path(&self.archive_dir, hash).exists());
+            if let Some(hash) = stored.filter(|_| !stamp.is_changed(&path)) {
+                return Ok(Some(BlobRef {
+                    hash: hash.clone(),
+                    size: stamp.len,
+                    changed: false,
+                    counts: None,
+                }));
+            }
+        }
+        // 読んでいる間に書き換えられても次回は読み直すよう、先に更新日時を取っておく
+        let metadata = fs::metadata(&path)?;
+        let bytes = get_bytes(&path)?;
+        let hash = store_blob(&self.archive_dir, &bytes)?;
+        self.master_stamps.insert(
+            table_name.clone(),
+            FileStamp {
+                modified: metadata.modified().ok(),
+                len: metadata.len(),
+                hash: Some(hash.clone()),
+            },
+        );
+        Ok(Some(BlobRef {
+            hash,
+            size: bytes.len() as u64,
+            changed: false,
+            counts: None,
+        }))
+    }
+}
+impl Archive for FolderArchive {
+    fn name(&self) -> &'static str {
+        "folder"
+    }
+    /// 変更したテーブルと、変更していないテーブルのマスタファイルの内容をハッシュで保存し、マニフェストを書く
+    /// 同じ内容は一度しか保存されない
+    fn before_write(
+        &mut self,
+        changes: &[TableChange],
+        tables: &[OsString],
+        info: &SnapshotInfo,
+    ) -> Result<()> {
+        let mut blobs = BTreeMap::new();
+        for change in changes.iter() {
+            let blob = BlobRef {
+                hash: store_blob(&self.archive_dir, change.bytes)?,
+                size: change.bytes.len() as u64,
+                changed: true,
+                counts: Some(change.counts),
+            };
+            blobs.insert(change.table_name.clone(), blob);
+        }
+        for table_name in tables.iter() {
+            if blobs.contains_key(table_name) {
+                continue;
+            }
+            if let Some(blob) = self.store_master_blob(table_name)? {
+                blobs.insert(table_name.clone(), blob);
+            }
+        }
+        let now_str = Local::now().format("%Y-%m-%d-%H%M%S-%Z").to_string();
+        self.pending = Some(write_manifest(&self.archive_dir, &now_str, info, &blobs)?);
+        Ok(())
+    }
+    /// マスタの置き換えに失敗した場合は、そのスナップショットも残さない
+    fn after_write(
+        &mut self,
+        changes: &[TableChange],
+        _info: &SnapshotInfo,
+        written: bool,
+    ) -> Result<()> {
+        let Some(save_dir) = self.pending.take() else {
+            return Ok(());
+        };
+        if !written {
+            let _ = fs::remove_dir_all(&save_dir);
+            return Ok(());
+        }
+        for change in changes.iter() {
+            let path = self.master_dir.join(change.table_name);
+            if let Ok(stamp) = FileStamp::written(&path, change.bytes) {
+                self.master_stamps.insert(change.table_name.clone(), stamp);
+            }
+        }
+        Ok(())
+    }
+    fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        list_snapshots(&self.archive_dir)
+    }
+    fn table_path(&self, snapshot: &Snapshot, table_name: &OsString) -> Result<PathBuf> {
+        Ok(snapshot.table_path(table_name))
+    }
+    fn can_prune(&self) -> bool {
+        true
+    }
+    fn prune(&mut self, snapshots: &[&Snapshot]) -> Result<usize> {
+        prune(&self.archive_dir, snapshots)
+    }
+}
+
+/// マスタディレクトリのgitリポジトリに、変更したテーブルをコミットする
+/// コミットはローカルだけで、pushはしない
+pub struct GitArchive {
+    master_dir: PathBuf,
+    /// 履歴のテーブルを閲覧するために書き出すディレクトリ
+    cache_dir: PathBuf,
+}
+impl GitArchive {
+    /// マスタディレクトリがgitリポジトリの中になければエラー
+    pub fn new(master_dir: &Path, archive_dir: &Path) -> Result<GitArchive> {
+        let archive = GitArchive {
+            master_dir: master_dir.to_path_buf(),
+            cache_dir: archive_dir.join(".git-cache"),
+        };
+        archive
+            .git(["rev-parse", "--show-toplevel"])
+            .with_context(|| format!("{} is not in a git repository", master_dir.display()))?;
+        Ok(archive)
+    }
+    /// マスタディレクトリでgitを実行し、標準出力を返す
+    fn git<I, S>(&self, args: I) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = self.command(args).output().context("couldn't run git")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "git: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        Ok(output.stdout)
+    }
+    /// マスタディレクトリでgitを実行し、成功したかを返す
+    fn git_succeeds<I, S>(&self, args: I) -> Result<bool>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = self.command(args).output().context("couldn't run git")?;
+        Ok(output.status.success())
+    }
+    fn command<I, S>(&self, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = Command::new("git");
+        // 日本語のファイル名をエスケープさせない
+        command
+            .arg("-C")
+            .arg(&self.master_dir)
+            .args(["-c", "core.quotePath=false"])
+            .args(args);
+        command
+    }
+    /// コミット時点のマスタディレクトリ直下のCSVファイルと大きさ
+    fn tables_at(&self, commit: &str) -> Result<BTreeMap<OsString, (String, u64)>> {
+        let output = self.git(["ls-tree", "-l", "-z", commit])?;
+        let mut tables = BTreeMap::new();
+        for entry in output.split(|b| *b == 0).filter(|e| !e.is_empty()) {
+            let entry = String::from_utf8_lossy(entry);
+            let Some((meta, name)) = entry.split_once('\t') else {
+                continue;
+            };
+            let fields: Vec<&str> = meta.split_whitespace().collect();
+            if fields.get(1) != Some(&"blob") || !name.ends_with(".csv") {
+                continue;
+            }
+            let hash = fields.get(2).unwrap_or(&"").to_string();
+            let size = fields.get(3).and_then(|s| s.parse().ok()).unwrap_or(0);
+            tables.insert(OsString::from(name), (hash, size));
+        }
+        Ok(tables)
+    }
+}
+
+/// コミットメッセージの本文に書く、テーブルごとの行数の変化
+fn counts_line(change: &TableChange) -> String {
+    let (added, removed, modified) = change.counts;
+    format!(
+        "{}: +{} -{} ~{}",
+        change.table_name.to_string_lossy(),
+        added,
+        removed,
+        modified
+    )
+}
+
+/// コミットメッセージの本文から、テーブルごとの行数の変化を読む
+fn parse_counts(body: &str) -> BTreeMap<OsString, (usize, usize, usize)> {
+    let mut counts = BTreeMap::new();
+    for line in body.lines() {
+        let Some((table_name, rest)) = line.rsplit_once(": ") else {
+            continue;
+        };
+        let numbers: Vec<usize> = rest
+            .split(' ')
+            .zip(['+', '-', '~'])
+            .filter_map(|(n, sign)| n.strip_prefix(sign)?.parse().ok())
+            .collect();
+        if let [added, removed, modified] = numbers[..] {
+            counts.insert(OsString::from(table_name), (added, removed, modified));
+        }
+    }
+    counts
+}
+
+impl Archive for GitArchive {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+    fn before_write(
+        &mut self,
+        _changes: &[TableChange],
+        _tables: &[OsString],
+        _info: &SnapshotInfo,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// 書き換えたテーブルだけをステージしてコミットする。他にステージされている変更は含めない
+    fn after_write(
+        &mut self,
+        changes: &[TableChange],
+        info: &SnapshotInfo,
+        written: bool,
+    ) -> Result<()> {
+        if !written || changes.is_empty() {
+            return Ok(());
+        }
+        let files: Vec<&OsString> = changes.iter().map(|c| c.table_name).collect();
+        let mut add: Vec<&OsStr> = vec![OsStr::new("add"), OsStr::new("--")];
+        add.extend(files.iter().map(|f| f.as_os_str()));
+        self.git(&add)?;
+
+        // 内容がコミット済みのものと同じならコミットしない
+        let mut diff: Vec<&OsStr> = ["diff", "--cached", "--quiet", "HEAD", "--"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect();
+        diff.extend(files.iter().map(|f| f.as_os_str()));
+        let has_head = self.git_succeeds(["rev-parse", "--verify", "-q", "HEAD"])?;
+        if has_head && self.git_succeeds(&diff)? {
+            return Ok(());
+        }
+
+        let subject = match info.message.is_empty() {
+            true => {
+                let names: Vec<String> = files
+                    .iter()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .collect();
+                format!("Update {}", names.join(", "))
+            }
+            false => info.message.clone(),
+        };
+        let body: Vec<String> = changes.iter().map(counts_line).collect();
+        let body = body.join("\n");
+        let mut commit: Vec<&OsStr> = ["commit", "-q", "-m", &subject, "-m", &body, "--"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect();
+        commit.extend(files.iter().map(|f| f.as_os_str()));
+        self.git(&commit)
+            .context("couldn't commit the saved tables")?;
+        Ok(())
+    }
+    fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        // まだコミットがなければ履歴もない
+        if !self.git_succeeds(["rev-parse", "--verify", "-q", "HEAD"])? {
+            return Ok(Vec::new());
+        }
+        let limit = format!("-n{}", GIT_LOG_LIMIT);
+        let output = self.git([
+            "log",
+            &limit,
+            "--relative",
+            "--name-only",
+            "--date=format-local:%Y-%m-%d-%H%M%S",
+            "--format=%x1e%H%x1f%ad%x1f%an%x1f%s%x1f%b%x1f",
+            "--",
+            ".",
+        ])?;
+        let output = String::from_utf8_lossy(&output);
+        let mut snapshots = Vec::new();
+        for record in output.split('\x1e').filter(|r| !r.trim().is_empty()) {
+            let fields: Vec<&str> = record.split('\x1f').collect();
+            let [commit, date, author, subject, body, names] = fields[..] else {
+                continue;
+            };
+            let changed: BTreeSet<OsString> = names
+                .lines()
+                .map(str::trim)
+                .filter(|n| n.ends_with(".csv") && !n.contains('/'))
+                .map(OsString::from)
+                .collect();
+            let counts = parse_counts(body);
+            let blobs: BTreeMap<OsString, BlobRef> = self
+                .tables_at(commit)?
+                .into_iter()
+                .map(|(table_name, (hash, size))| {
+                    let blob = BlobRef {
+                        hash,
+                        size,
+                        changed: changed.contains(&table_name),
+                        counts: counts.get(&table_name).copied(),
+                    };
+                    (table_name, blob)
+                })
+                .collect();
+            snapshots.push(Snapshot {
+                name: format!("{} {}", date, commit.get(..7).unwrap_or(commit)),
+                path: self.master_dir.clone(),
+                tables: blobs.keys().cloned().collect(),
+                size: 0,
+                blobs,
+                info: Some(SnapshotInfo {
+                    message: subject.to_string(),
+                    user: author.to_string(),
+                    host: String::new(),
+                }),
+                commit: Some(commit.to_string()),
+                unreadable: None,
+            });
+        }
+        Ok(snapshots)
+    }
+    /// コミット時点の内容を閲覧用のディレクトリに書き出して、そのパスを返す
+    fn table_path(&self, snapshot: &Snapshot, table_name: &OsString) -> Result<PathBuf> {
+        let commit = snapshot.commit.as_deref().context("not a git snapshot")?;
+        let path = self.cache_dir.join(commit).join(table_name);
+        if !path.exists() {
+            let mut spec = OsString::from(format!("{}:./", commit));
+            spec.push(table_name);
+            let content = self.git([OsStr::new("show"), spec.as_os_str()])?;
+            save_to_file(content, path.clone())?;
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_counts_from_commit_body() {
+        let counts = parse_counts(
+            "items.csv: +1 -2 ~3\nnot a count\nweird: name.csv: +0 -0 ~4\nbad.csv: +1 -x ~2\n",
+        );
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&OsString::from("items.csv")], (1, 2, 3));
+        assert_eq!(counts[&OsString::from("weird: name.csv")], (0, 0, 4));
+    }
+}
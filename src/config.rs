@@ -9,6 +9,49 @@ const DEFAULT_CONFIG: &str = r#"
 [master]
 directory = "{CUR}/data/master_csv/"
 history = "{CUR}/data/history/"
+
+[archive]
+# 保存した内容を履歴に残す方法
+# "folder": master.history にスナップショットを作る
+# "git": マスタディレクトリのgitリポジトリに、変更したテーブルをコミットする（pushはしない）
+backend = "folder"
+
+[autosave]
+# 編集中のテーブルをスワップファイルに書き出す間隔（秒）
+interval = 30
+
+[lock]
+# 更新されなくなったロック・スワップファイルを、放棄されたとみなすまでの秒数
+stale_after = 300
+
+[retention]
+# 履歴の整理の規則。指定した規則のいずれかに当てはまるスナップショットを残し、残りは保存後に消す
+# 新しいものから残す数
+# keep_last = 50
+# 何日前まで、日ごとに最後のスナップショットを残すか
+# keep_daily = 30
+# 履歴全体の大きさの上限（MB）。超えたら古いものから消す
+# max_size_mb = 1024
+
+[display]
+# 日付・時刻の列の表示形式（strftime形式）。ファイルには元の値のまま保存する
+date = "%Y-%m-%d"
+datetime = "%Y-%m-%d %H:%M:%S"
+datetime_tz = "%Y-%m-%d %H:%M:%S %:z"
+time = "%H:%M:%S"
+
+[null]
+# 値がないこと（NULL）を表す文字列。空文字を含めなければ、空文字は空の文字列として扱う
+# 空文字を含めると空の文字列はすべてNULLとして表示・検査され、NULLを許さない列では違反になる
+# 既定では空文字を含めず、空の文字列とNULLを区別する
+tokens = ["\\N", "NULL", "NA"]
+# 値をNULLにするときに書き込む文字列。空の文字列と区別できるよう \N を書くが、
+# \N をNULLと読まない表計算ソフトなどでは文字列として見える。空文字にすると tokens にも加わる
+write = "\\N"
+
+[diff.keys]
+# 差分で行を対応付けるキー列（ファイル名 = 列名）。指定のないテーブルは行の並びで対応付ける
+# "items.csv" = "id"
 "#;
 
 // toml形式の設定ファイルを読み込む
@@ -25,7 +68,7 @@ pub fn load_config() -> Result<Value> {
     let mut conf_toml_str = String::new();
     // 全ファイルをテキストで読み込み
     for path in file_paths.iter() {
-        conf_toml_str = format!("{}{}", conf_toml_str, get_text(Path::new(&path)));
+        conf_toml_str = format!("{}{}", conf_toml_str, get_text(Path::new(&path))?);
     }
 
     // 設定を1件も取得できていなければ
@@ -34,10 +77,10 @@ pub fn load_config() -> Result<Value> {
         let path_str = format!("{}//config//config.toml", &cur_dir);
         let path = Path::new(&path_str);
         let dir = path.parent().unwrap();
-        DirBuilder::new().recursive(true).create(&dir)?;
+        DirBuilder::new().recursive(true).create(dir)?;
 
         // パスを書き込みモードで開く
-        let file = match File::create(&path) {
+        let file = match File::create(path) {
             Err(e) => panic!("couldn't create {}: {}", path_str, &e.to_string()),
             Ok(file) => file,
         };
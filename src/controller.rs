@@ -9,15 +9,24 @@ mod import {
         terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     };
 
-    pub use crate::model::{DataTable, StatefulList};
+    pub use crate::archive::{Archive, TableChange};
+    pub use crate::diff::{diff_tables_by, TableDiff, Version};
+    pub use crate::error::LoadError;
+    pub use crate::history::{format_size, freed_size, Retention, Snapshot, SnapshotInfo};
+    pub use crate::lock::{is_abandoned, LockOwner, LockResult, TableLock};
+    pub use crate::model::{Column, DataTable, DataType, NullTokens, StatefulList};
     pub use crate::prelude::*;
+    pub use crate::schema::{read_sidecar, sidecar_path, write_sidecar};
+    pub use crate::temporal::DisplayFormat;
+    pub use crate::validate::validate_table;
+    pub use std::time::{Duration, Instant};
     pub use tui::{
         backend::{Backend, CrosstermBackend},
         layout::{Alignment, Constraint, Direction, Layout, Rect},
         style::{Color, Modifier, Style},
         text,
         widgets::{Block, Borders, ListItem, Paragraph},
-        Terminal,
+        Frame, Terminal,
     };
     pub use tui_textarea::{Input, Key, TextArea};
 }
@@ -30,88 +39,1574 @@ pub enum ConsoleState {
     Select(Option<String>),
     EditTable(String),
     EditRow(String),
+    EditHeader(String),
+    /// 前回の自動保存・退避データから復元するテーブルを選ぶ
+    Recover,
+    /// 履歴のスナップショットを閲覧・復元する
+    History,
+    /// 全テーブルを列の型と宣言された規則で検査し、違反の一覧を表示する
     CheckIntegrity,
     Quit,
 }
 
-type DataTables = BTreeMap<OsString, DataTable>;
+/// テーブル一覧の各エントリ。テーブルは開かれたときに初めて読み込む
+pub enum TableEntry {
+    Unloaded(PathBuf),
+    Loaded(Box<DataTable>),
+    /// 読み込みに失敗した。開き直すと再度読み込む
+    Failed(PathBuf, LoadError),
+}
+impl TableEntry {
+    fn table(&self) -> Option<&DataTable> {
+        match self {
+            TableEntry::Loaded(data_table) => Some(data_table),
+            _ => None,
+        }
+    }
+    fn table_mut(&mut self) -> Option<&mut DataTable> {
+        match self {
+            TableEntry::Loaded(data_table) => Some(data_table),
+            _ => None,
+        }
+    }
+}
+
+type DataTables = BTreeMap<OsString, TableEntry>;
 
 pub struct App {
     state: ConsoleState,
     data_tables: DataTables,
-    archive_dir: String,
+    /// 保存した内容を履歴として残す方法
+    archive: Box<dyn Archive>,
     master_dir: String,
+    /// 異常終了時にテーブルを退避するディレクトリ
+    recovery_dir: PathBuf,
+    /// 自動保存のスワップファイルを置く隠しディレクトリ。プロセスごとにサブディレクトリを分ける
+    swap_root: PathBuf,
+    /// このプロセスのスワップファイルを置くディレクトリ
+    swap_dir: PathBuf,
+    /// スワップファイルの持ち主としてスワップディレクトリに書く情報
+    swap_owner: LockOwner,
+    autosave_interval: Duration,
+    last_autosave: Instant,
+    /// テーブルごとの、最後にスワップファイルへ書き出した世代
+    swapped: BTreeMap<OsString, u64>,
+    /// 他のプロセスと編集が重ならないようにするロックファイルを置く隠しディレクトリ
+    lock_dir: PathBuf,
+    stale_after: Duration,
+    /// 編集のために保持しているロック
+    locks: BTreeMap<OsString, TableLock>,
+    /// テーブルごとの、差分で行を対応付けるキー列
+    diff_keys: BTreeMap<OsString, String>,
+    /// 履歴を残す規則
+    retention: Retention,
+    /// スナップショットをCSVのフォルダとして書き出す先
+    export_dir: PathBuf,
+    /// 日付・時刻の列の表示形式
+    display: DisplayFormat,
+    /// 値がないことを表す文字列
+    nulls: NullTokens,
+    /// 画面を移るときに、次の画面のステータス行に引き継ぐメッセージ
+    status: String,
+}
+
+/// 前回の実行から残っている復元候補
+struct RecoveryCandidate {
+    table_name: OsString,
+    path: PathBuf,
+    /// "swap"（自動保存）か "dump"（異常終了時の退避）
+    kind: &'static str,
+}
+
+/// 復元画面の1項目
+struct Recovery {
+    candidate: RecoveryCandidate,
+    /// 読み込めなかった場合はNone
+    table: Option<DataTable>,
+    label: String,
+    detail: Vec<text::Spans<'static>>,
+}
+
+/// 整合性チェックの画面の1項目
+struct Finding {
+    table_name: String,
+    /// 移動先のセル（行, 列）。テーブルを読み込めなかった場合などはNone
+    cell: Option<(usize, usize)>,
+    label: ListItem<'static>,
+}
+
+/// 整合性チェックで1つのテーブルについて一覧に載せる違反の数
+const MAX_FINDINGS_PER_TABLE: usize = 1000;
+
+/// スワップディレクトリに置く、持ち主の情報のファイル名。自動保存のたびに書き直す
+const SWAP_OWNER_FILE: &str = "owner.toml";
+
+/// 行の編集画面に一度に表示する項目の数。これより多い列は編集中の項目を含む分だけ表示する
+const FIELDS_PER_PAGE: usize = 5;
+
+/// 指定ディレクトリに残っているCSVファイル
+fn leftover_files(dir: &Path) -> Vec<PathBuf> {
+    match dir.is_dir() {
+        true => glob(&dir.display().to_string(), "csv", false)
+            .unwrap_or_default()
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+        false => Vec::new(),
+    }
+}
+
+/// 退避ディレクトリとスワップディレクトリに残っている復元候補
+/// スワップファイルは、持ち主のプロセスが終了したもの（放棄されたもの）だけを候補にする
+fn recovery_candidates(
+    recovery_dir: &Path,
+    swap_root: &Path,
+    stale_after: Duration,
+) -> Vec<RecoveryCandidate> {
+    let dumps = leftover_files(recovery_dir)
+        .into_iter()
+        .map(|p| (p, "dump"));
+    let abandoned: Vec<PathBuf> = std::fs::read_dir(swap_root)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|dir| dir.is_dir() && is_abandoned(&dir.join(SWAP_OWNER_FILE), stale_after))
+        .collect();
+    // 直下にあるものは、プロセスごとに分ける前の形式で書かれたもの
+    let swaps = leftover_files(swap_root)
+        .into_iter()
+        .chain(abandoned.iter().flat_map(|dir| leftover_files(dir)))
+        .map(|p| (p, "swap"));
+    dumps
+        .chain(swaps)
+        .filter_map(|(path, kind)| {
+            let table_name = path.file_name()?.to_os_string();
+            Some(RecoveryCandidate {
+                table_name,
+                path,
+                kind,
+            })
+        })
+        .collect()
+}
+
+/// 復元・破棄した候補のファイルを消す
+/// スワップファイルのディレクトリは、スワップファイルが残っていなければ一緒に消す
+fn remove_candidate(path: &Path) -> Result<()> {
+    std::fs::remove_file(path)?;
+    let Some(dir) = path.parent() else {
+        return Ok(());
+    };
+    if dir.join(SWAP_OWNER_FILE).exists() && leftover_files(dir).is_empty() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// 背景の画面の上に確認ダイアログを表示し、選択肢のいずれかのキーが押されるまで待つ
+fn ask<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut background: impl FnMut(&mut Frame<B>),
+    title: &str,
+    lines: &[String],
+    choices: &[(KeyCode, &str)],
+) -> Result<KeyCode> {
+    let hint = choices
+        .iter()
+        .map(|(code, label)| match code {
+            KeyCode::Char(c) => format!("{}: {}", c, label),
+            KeyCode::Esc => format!("Esc: {}", label),
+            KeyCode::Enter => format!("Enter: {}", label),
+            code => format!("{:?}: {}", code, label),
+        })
+        .collect::<Vec<String>>()
+        .join("  ");
+    loop {
+        terminal.draw(|f| {
+            background(f);
+            ui::confirm(f, title, lines, &hint);
+        })?;
+        if let Event::Key(key) = event::read()? {
+            if choices.iter().any(|(code, _)| *code == key.code) {
+                return Ok(key.code);
+            }
+        }
+    }
+}
+
+/// ファイルを比較用に読み込む。存在しない・読めない場合は空の版とする
+fn load_version(path: &Path, label: String) -> Version {
+    let loaded = DataTable::load(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|t| Ok((t.header(), t.to_rows()?)));
+    match loaded {
+        Ok((header, rows)) => Version {
+            label,
+            header,
+            rows,
+        },
+        Err(_) => Version {
+            label: format!("{} (なし)", label),
+            ..Version::default()
+        },
+    }
+}
+
+/// 背景の画面の上に1行の入力欄を表示する。Enterで入力内容を、Escで取消としてNoneを返す
+fn prompt<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut background: impl FnMut(&mut Frame<B>),
+    title: &str,
+    hint: &str,
+) -> Result<Option<String>> {
+    let mut textarea = TextArea::default();
+    textarea.set_cursor_line_style(Style::default());
+    textarea.set_block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Yellow))
+            .title(title.to_string()),
+    );
+    loop {
+        terminal.draw(|f| {
+            background(f);
+            ui::prompt(f, &textarea, hint);
+        })?;
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Enter => return Ok(Some(textarea.lines().join(" ").trim().to_string())),
+                KeyCode::Esc => return Ok(None),
+                _ => {
+                    textarea.input(Input::from(key));
+                }
+            }
+        }
+    }
+}
+
+/// 背景の画面の上に選択肢の一覧を表示する。Enterで選んだ位置を、Escで取消としてNoneを返す
+fn pick<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut background: impl FnMut(&mut Frame<B>),
+    title: &str,
+    items: &[String],
+    selected: Option<usize>,
+) -> Result<Option<usize>> {
+    let items = items.iter().map(|i| ListItem::new(i.clone())).collect();
+    let mut list = StatefulList::with_items(items);
+    list.state.select(Some(selected.unwrap_or(0)));
+    loop {
+        terminal.draw(|f| {
+            background(f);
+            ui::picker(f, title, &mut list);
+        })?;
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Enter => return Ok(list.state.selected()),
+                KeyCode::Esc => return Ok(None),
+                KeyCode::Down => list.next(),
+                KeyCode::Up => list.previous(),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// キー入力を最大1秒待つ。入力がなければNoneを返し、呼び出し側はその間に自動保存を行う
+fn poll_event() -> Result<Option<Event>> {
+    match event::poll(Duration::from_secs(1))? {
+        true => Ok(Some(event::read()?)),
+        false => Ok(None),
+    }
 }
 
 impl App {
-    pub fn new(config: &Value) -> Self {
-        let archive_dir = config["master"]["history"].as_str().unwrap();
+    pub fn new(config: &Value) -> Result<Self> {
+        let master = config.get("master");
+        let archive_dir = master
+            .and_then(|m| m.get("history"))
+            .and_then(Value::as_str)
+            .context("master.history is not set in config")?;
         let archive_dir = String::from(archive_dir);
 
-        let master_dir = config["master"]["directory"].as_str().unwrap();
-        let csv_paths = glob(master_dir, "csv", false).unwrap();
+        let master_dir = master
+            .and_then(|m| m.get("directory"))
+            .and_then(Value::as_str)
+            .context("master.directory is not set in config")?;
+        let csv_paths = glob(master_dir, "csv", false)
+            .with_context(|| format!("couldn't read master directory {}", master_dir))?;
+        let archive =
+            crate::archive::from_config(config, Path::new(master_dir), Path::new(&archive_dir))?;
 
-        // data_tablesフィールドの作成
+        // data_tablesフィールドの作成。ファイルの中身は開くまで読まない
         let mut data_tables: DataTables = BTreeMap::new();
         for path in csv_paths.iter().map(Path::new) {
-            let data = get_string_records(path).unwrap();
-            let max_len = data.iter().map(|e| e.len()).max().unwrap();
-            let mut data: Vec<Vec<String>> = data
+            let fname = path.file_name().unwrap().to_os_string();
+            data_tables.insert(fname, TableEntry::Unloaded(path.to_path_buf()));
+        }
+
+        // 退避ディレクトリは履歴ディレクトリと同じ階層に置く
+        let archive_path = Path::new(&archive_dir);
+        let recovery_dir = archive_path
+            .parent()
+            .unwrap_or(archive_path)
+            .join("recovery");
+
+        let export_dir = archive_path.parent().unwrap_or(archive_path).join("export");
+
+        // スワップファイルは共有のマスタディレクトリに置くので、ホスト・PIDごとに分ける
+        let swap_root = Path::new(master_dir).join(".swap");
+        let swap_owner = LockOwner::current();
+        let swap_dir = swap_root.join(format!(
+            "{}.{}.{}",
+            swap_owner.host,
+            swap_owner.pid,
+            Local::now().format("%Y%m%d%H%M%S")
+        ));
+        let autosave_interval = config
+            .get("autosave")
+            .and_then(|a| a.get("interval"))
+            .and_then(Value::as_integer)
+            .unwrap_or(30);
+
+        let diff_keys = config
+            .get("diff")
+            .and_then(|d| d.get("keys"))
+            .and_then(Value::as_table)
+            .map(|keys| {
+                keys.iter()
+                    .filter_map(|(table, key)| {
+                        Some((OsString::from(table), key.as_str()?.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let lock_dir = Path::new(master_dir).join(".lock");
+        let stale_after = config
+            .get("lock")
+            .and_then(|l| l.get("stale_after"))
+            .and_then(Value::as_integer)
+            .unwrap_or(300);
+
+        // 前回の退避データ・スワップファイルが残っていれば復元を提案する
+        let stale_after = Duration::from_secs(stale_after.max(1) as u64);
+        let state = match recovery_candidates(&recovery_dir, &swap_root, stale_after).is_empty() {
+            true => ConsoleState::Start,
+            false => ConsoleState::Recover,
+        };
+
+        Ok(Self {
+            state,
+            data_tables,
+            master_dir: master_dir.to_string(),
+            archive,
+            recovery_dir,
+            swap_root,
+            swap_dir,
+            swap_owner,
+            autosave_interval: Duration::from_secs(autosave_interval.max(1) as u64),
+            last_autosave: Instant::now(),
+            swapped: BTreeMap::new(),
+            lock_dir,
+            stale_after,
+            locks: BTreeMap::new(),
+            diff_keys,
+            retention: Retention::from_config(config),
+            export_dir,
+            display: DisplayFormat::from_config(config)?,
+            nulls: NullTokens::from_config(config)?,
+            status: String::new(),
+        })
+    }
+
+    /// 編集済みのテーブルを退避ディレクトリに書き出す
+    /// パニックやエラーで終了するときに呼ばれるので、書き出せなかったテーブルは飛ばして続ける
+    pub fn dump_for_recovery(&self) -> Vec<PathBuf> {
+        let mut dumped = Vec::new();
+        for (table_name, entry) in self.data_tables.iter() {
+            if let Some(data_table) = entry.table().filter(|t| t.is_modified()) {
+                let path = self.recovery_dir.join(table_name);
+                if let Ok(bytes) = data_table.bytes() {
+                    if save_to_file(bytes, path.clone()).is_ok() {
+                        dumped.push(path);
+                    }
+                }
+            }
+        }
+        dumped
+    }
+    /// 前回から間隔が空いていれば、編集されたテーブルをスワップファイルに書き出す
+    /// 自動保存は補助的なものなので、書き出せなかったテーブルは次の機会に回す
+    fn autosave_if_due(&mut self) -> Result<()> {
+        if self.last_autosave.elapsed() < self.autosave_interval {
+            return Ok(());
+        }
+        self.last_autosave = Instant::now();
+        // 保持しているロックがまだ使われていることを示す
+        for lock in self.locks.values() {
+            let _ = lock.refresh();
+        }
+        // スワップファイルの持ち主がまだ動いていることを示す
+        let owner_path = self.swap_dir.join(SWAP_OWNER_FILE);
+        if self.swap_dir.is_dir() {
+            let _ = save_to_file(self.swap_owner.to_toml(), owner_path.clone());
+        }
+        for (table_name, entry) in self.data_tables.iter() {
+            let Some(data_table) = entry.table().filter(|t| t.is_modified()) else {
+                continue;
+            };
+            let swapped = self.swapped.get(table_name).copied().unwrap_or(0);
+            if data_table.generation == swapped {
+                continue;
+            }
+            // 持ち主の情報のないスワップファイルは、他のプロセスに放棄されたものとみなされない
+            if !owner_path.exists()
+                && save_to_file(self.swap_owner.to_toml(), owner_path.clone()).is_err()
+            {
+                continue;
+            }
+            if let Ok(bytes) = data_table.bytes() {
+                if save_to_file(bytes, self.swap_dir.join(table_name)).is_ok() {
+                    self.swapped
+                        .insert(table_name.clone(), data_table.generation);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// 未保存の変更があるテーブル名
+    fn modified_table_names(&self) -> Vec<OsString> {
+        self.data_tables
+            .iter()
+            .filter(|(_, entry)| entry.table().is_some_and(DataTable::is_modified))
+            .map(|(table_name, _)| table_name.clone())
+            .collect()
+    }
+    /// 編集されたテーブルだけを履歴ディレクトリに書き出してからマスタに反映し、保存した数を返す
+    fn save_modified(&mut self, message: &str) -> Result<usize> {
+        let modified = self.modified_table_names();
+        if modified.is_empty() {
+            return Ok(0);
+        }
+
+        // 他で編集中のテーブルがあれば何も書き込まない
+        for table_name in modified.iter() {
+            let name = table_name.to_string_lossy().into_owned();
+            self.lock_table(&name);
+            if let Some(reason) = self.get_table(name.as_str()).unwrap().read_only.clone() {
+                anyhow::bail!("{}: {}", name, reason);
+            }
+        }
+
+        // 先にすべてのテーブルを変換しておき、変換できないテーブルがあれば何も書き込まない
+        let mut contents = Vec::new();
+        let mut counts = BTreeMap::new();
+        for table_name in modified.iter() {
+            let data_table = self.get_table(table_name.clone()).unwrap();
+            let bytes = data_table
+                .bytes()
+                .with_context(|| format!("{}", table_name.to_string_lossy()))?;
+            contents.push((table_name, bytes));
+            counts.insert(table_name.clone(), self.change_counts(table_name)?);
+        }
+
+        // 履歴に残してから、マスタをまとめて置き換える
+        // マスタの置き換えに失敗した場合は、履歴にも残さない
+        let changes: Vec<TableChange> = contents
+            .iter()
+            .map(|(table_name, bytes)| TableChange {
+                table_name,
+                bytes,
+                counts: counts[*table_name],
+            })
+            .collect();
+        let tables: Vec<OsString> = self.data_tables.keys().cloned().collect();
+        let info = SnapshotInfo::current(message);
+        self.archive.before_write(&changes, &tables, &info)?;
+        let master_files: Vec<(PathBuf, Vec<u8>)> = contents
+            .iter()
+            .map(|(table_name, bytes)| {
+                (Path::new(&self.master_dir).join(table_name), bytes.clone())
+            })
+            .collect();
+        let written = save_files_atomically(&master_files);
+        let archived = self.archive.after_write(&changes, &info, written.is_ok());
+        written?;
+
+        for (table_name, (path, bytes)) in modified.iter().zip(master_files.iter()) {
+            self.clear_swap(table_name)?;
+            self.locks.remove(table_name);
+            let data_table = self.get_table_mut(table_name.clone()).unwrap();
+            data_table.mark_saved();
+            data_table.origin = FileStamp::written(path, bytes).ok();
+            // 索引はマスタファイル上のオフセットなので、書き換えたら読み直す
+            if data_table.rows.is_indexed() {
+                self.data_tables
+                    .insert(table_name.clone(), TableEntry::Unloaded(path.clone()));
+            }
+        }
+        archived.context("saved to master, but couldn't record the history")?;
+        Ok(modified.len())
+    }
+    /// マスタファイルに対して追加・削除・変更された行数
+    /// 読み込んだときの内容を持っていないテーブルはマスタファイルを読んで比べる
+    fn change_counts(&self, table_name: &OsString) -> Result<(usize, usize, usize)> {
+        let data_table = self.get_table(table_name.clone()).unwrap();
+        let (header, rows) = (data_table.header(), data_table.to_rows()?);
+        let key = self.diff_key(table_name);
+        let diff = match &data_table.base {
+            Some(base) => diff_tables_by(&base.header, &base.rows, &header, &rows, key),
+            None => {
+                let path = Path::new(&self.master_dir).join(table_name);
+                let master = load_version(&path, String::new());
+                diff_tables_by(&master.header, &master.rows, &header, &rows, key)
+            }
+        };
+        Ok(diff.counts())
+    }
+    /// 履歴を残す規則に当てはまらないスナップショットを消し、消した数を返す
+    fn prune_history(&mut self) -> Result<usize> {
+        if !self.retention.is_enabled() || !self.archive.can_prune() {
+            return Ok(0);
+        }
+        let snapshots = self.archive.snapshots()?;
+        let today = Local::now().date_naive();
+        self.archive.prune(&self.retention.plan(&snapshots, today))
+    }
+    /// テーブルのロックを取る。他で編集中なら読み取り専用にする
+    /// 放棄されたロックを解除した場合は、そのことを知らせるメッセージを返す
+    fn lock_table(&mut self, table_name: &str) -> Option<String> {
+        let key = OsString::from(table_name);
+        if self.locks.get(&key).is_some_and(TableLock::is_held) {
+            return None;
+        }
+        let path = self.lock_dir.join(format!("{}.lock", table_name));
+        let (read_only, message) = match TableLock::acquire(&path, self.stale_after) {
+            Ok(LockResult::Acquired { lock, broken }) => {
+                self.locks.insert(key.clone(), lock);
+                let message = broken.map(|owner| {
+                    format!("{} の放棄されたロックを解除しました: {}", table_name, owner)
+                });
+                (None, message)
+            }
+            Ok(LockResult::HeldBy(owner)) => {
+                self.locks.remove(&key);
+                (Some(format!("{} が編集中です", owner)), None)
+            }
+            // ロックを確かめられないときは編集させない
+            Err(e) => {
+                self.locks.remove(&key);
+                (Some(format!("ロックを取れませんでした: {}", e)), None)
+            }
+        };
+        if let Some(data_table) = self.get_table_mut(key) {
+            data_table.read_only = read_only;
+        }
+        message
+    }
+    /// 未保存の変更がなければロックを解除する
+    fn unlock_if_idle(&mut self, table_name: &str) {
+        let key = OsString::from(table_name);
+        if !self
+            .get_table(key.clone())
+            .is_some_and(DataTable::is_modified)
+        {
+            self.locks.remove(&key);
+        }
+    }
+    /// 編集中のテーブルのうち、読み込んでから他でマスタファイルが変更されたもの
+    fn external_conflicts(&self) -> Vec<OsString> {
+        self.modified_table_names()
+            .into_iter()
+            .filter(|table_name| {
+                let path = Path::new(&self.master_dir).join(table_name);
+                self.get_table(table_name.clone())
+                    .and_then(|t| t.origin.as_ref())
+                    .is_some_and(|origin| origin.is_changed(&path))
+            })
+            .collect()
+    }
+    /// 他で変更されたテーブルごとに差分を見せ、読み直す・上書きする・マージするかを選ばせる
+    /// 1つでもキャンセルされたらfalseを返し、呼び出し側は保存を取りやめる
+    fn resolve_conflicts<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<bool> {
+        for table_name in self.external_conflicts() {
+            let name = table_name.to_string_lossy().into_owned();
+            let path = Path::new(&self.master_dir).join(&table_name);
+            // 消されていた場合は空のテーブルと比較する
+            let disk = DataTable::load(&path).ok();
+            let (disk_header, disk_rows) = match disk.as_ref() {
+                Some(disk) => (disk.header(), disk.to_rows()?),
+                None => (Vec::new(), Vec::new()),
+            };
+            let data_table = self.get_table(table_name.clone()).unwrap();
+            let (header, rows) = (data_table.header(), data_table.to_rows()?);
+            let key = self.diff_key(&table_name);
+            let diff = diff_tables_by(&disk_header, &disk_rows, &header, &rows, key);
+            let detail = ui::diff_lines(&diff, &disk_header, &disk_rows, &header, &rows);
+
+            let can_merge = disk.is_some() && data_table.base.is_some();
+            let mut hint = match disk.is_some() {
+                true => "r: 読み直して編集を破棄  o: 上書き".to_string(),
+                false => "o: 上書き".to_string(),
+            };
+            if can_merge {
+                hint += "  m: マージ";
+            }
+            hint += "  ↑↓: スクロール  Esc: 保存をやめる";
+            let mut message = String::new();
+            let mut scroll: u16 = 0;
+
+            loop {
+                let help = match message.is_empty() {
+                    true => hint.clone(),
+                    false => format!("{}  ({})", hint, message),
+                };
+                terminal.draw(|f| ui::conflict(f, &name, &detail, scroll, &help))?;
+                let Event::Key(key) = event::read()? else {
+                    continue;
+                };
+                match key.code {
+                    KeyCode::Down => scroll = scroll.saturating_add(1),
+                    KeyCode::Up => scroll = scroll.saturating_sub(1),
+                    KeyCode::Char('r') if disk.is_some() => {
+                        self.data_tables
+                            .insert(table_name.clone(), TableEntry::Unloaded(path.clone()));
+                        self.clear_swap(&table_name)?;
+                        self.locks.remove(&table_name);
+                        break;
+                    }
+                    KeyCode::Char('o') => {
+                        let data_table = self.get_table_mut(table_name.clone()).unwrap();
+                        data_table.origin = disk.as_ref().and_then(|d| d.origin.clone());
+                        break;
+                    }
+                    KeyCode::Char('m') if can_merge => {
+                        let data_table = self.get_table_mut(table_name.clone()).unwrap();
+                        let conflicts = match data_table.merge_from(disk.as_ref().unwrap()) {
+                            Ok(conflicts) => conflicts,
+                            Err(e) => {
+                                message = format!("merge failed: {}", e);
+                                continue;
+                            }
+                        };
+                        if conflicts == 0 {
+                            break;
+                        }
+                        // 両方で変更されたセルは編集中の値になっているので、確かめてから保存させる
+                        // 保存をやめてもマージした内容は編集中のテーブルに残る
+                        let lines = [format!(
+                            "{} 個のセルは両方で変更されていたため、編集中の値を残しました",
+                            conflicts
+                        )];
+                        loop {
+                            let choice = ask(
+                                terminal,
+                                |f| ui::conflict(f, &name, &detail, scroll, &hint),
+                                "マージの競合",
+                                &lines,
+                                &[
+                                    (KeyCode::Enter, "このまま保存"),
+                                    (KeyCode::Char('d'), "ディスク上の内容との差分"),
+                                    (KeyCode::Esc, "保存をやめる"),
+                                ],
+                            )?;
+                            match choice {
+                                KeyCode::Enter => break,
+                                KeyCode::Char('d') => {
+                                    let merged = self.get_table(table_name.clone()).unwrap();
+                                    let new = Version {
+                                        label: "マージ後".to_string(),
+                                        header: merged.header(),
+                                        rows: merged.to_rows()?,
+                                    };
+                                    let old = Version {
+                                        label: "ディスク".to_string(),
+                                        header: disk_header.clone(),
+                                        rows: disk_rows.clone(),
+                                    };
+                                    self.diff_view(terminal, &table_name, old, new)?;
+                                }
+                                _ => return Ok(false),
+                            }
+                        }
+                        break;
+                    }
+                    KeyCode::Esc => return Ok(false),
+                    _ => {}
+                }
+            }
+        }
+        Ok(true)
+    }
+    /// 保存済みになったテーブルのスワップファイルを消す
+    fn clear_swap(&mut self, table_name: &OsString) -> Result<()> {
+        if let Some(data_table) = self.get_table(table_name.clone()) {
+            let generation = data_table.generation;
+            self.swapped.insert(table_name.clone(), generation);
+        }
+        let path = self.swap_dir.join(table_name);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+    /// 復元候補を読み込み、マスタファイルとの差分を作る
+    fn prepare_recovery(&self, candidate: RecoveryCandidate) -> Recovery {
+        let name = candidate.table_name.to_string_lossy().into_owned();
+        let loaded = DataTable::load(&candidate.path)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut data_table| {
+                // 候補のファイルは後で消すので、行はすべてメモリに読み込んでおく
+                data_table.rows.load_all()?;
+                Ok(data_table)
+            });
+        let data_table = match loaded {
+            Ok(data_table) => data_table,
+            Err(e) => {
+                return Recovery {
+                    label: format!("{} ({})  failed to load: {}", name, candidate.kind, e),
+                    candidate,
+                    table: None,
+                    detail: Vec::new(),
+                }
+            }
+        };
+
+        // マスタに存在しないテーブルは空のテーブルと比較する
+        let master_path = Path::new(&self.master_dir).join(&candidate.table_name);
+        let (master_header, master_rows) = match DataTable::load(&master_path) {
+            Ok(master) => (master.header(), master.to_rows().unwrap_or_default()),
+            Err(_) => (Vec::new(), Vec::new()),
+        };
+        let rows = data_table.to_rows().unwrap_or_default();
+        let header = data_table.header();
+        let key = self.diff_key(&candidate.table_name);
+        let diff = diff_tables_by(&master_header, &master_rows, &header, &rows, key);
+        let (added, removed, changed) = diff.counts();
+
+        Recovery {
+            label: format!(
+                "{} ({})  +{} -{} ~{}",
+                name, candidate.kind, added, removed, changed
+            ),
+            detail: ui::diff_lines(&diff, &master_header, &master_rows, &header, &rows),
+            candidate,
+            table: Some(data_table),
+        }
+    }
+    /// 自動保存・退避データから復元するテーブルを選ぶ画面
+    fn recover<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<ConsoleState> {
+        let mut recoveries: Vec<Recovery> =
+            recovery_candidates(&self.recovery_dir, &self.swap_root, self.stale_after)
+                .into_iter()
+                .map(|candidate| self.prepare_recovery(candidate))
+                .collect();
+        let mut list = StatefulList::with_items(Vec::new());
+
+        loop {
+            if recoveries.is_empty() {
+                return Ok(ConsoleState::Select(None));
+            }
+            list.items = recoveries
                 .iter()
-                .map(|record| record.iter().map(String::from).collect())
+                .map(|r| ListItem::new(r.label.clone()))
                 .collect();
-            for record_vec in data.iter_mut() {
-                if record_vec.len() < max_len {
-                    let diff = max_len - record_vec.len();
-                    let mut v = vec![String::new(); diff];
-                    record_vec.append(&mut v);
+            if list.state.selected().is_none_or(|i| i >= recoveries.len()) {
+                list.state.select(Some(0));
+            }
+            let selected = list.state.selected().unwrap();
+
+            terminal.draw(|f| ui::recovery(f, &mut list, &recoveries[selected].detail))?;
+            if let Event::Key(key) = event::read()? {
+                match key.code {
+                    KeyCode::Down => list.next(),
+                    KeyCode::Up => list.previous(),
+                    // 復元して編集中のテーブルとして扱う
+                    KeyCode::Char('r') => {
+                        if recoveries[selected].table.is_none() {
+                            continue;
+                        }
+                        let recovery = recoveries.remove(selected);
+                        let mut data_table = recovery.table.unwrap();
+                        data_table.touch();
+                        // 他で変更されたかの判定とマージは、現在のマスタファイルを基準にする
+                        let master_path =
+                            Path::new(&self.master_dir).join(&recovery.candidate.table_name);
+                        let master = DataTable::load(&master_path).ok();
+                        // 退避したファイルの隣にはスキーマファイルがないので、マスタのものを使う
+                        data_table.set_null_tokens(self.nulls.clone());
+                        if let Ok(Some(specs)) = read_sidecar(&master_path) {
+                            data_table.apply_declared(&specs);
+                        }
+                        data_table.origin = master.as_ref().and_then(|m| m.origin.clone());
+                        data_table.base = master.and_then(|m| m.base);
+                        self.data_tables.insert(
+                            recovery.candidate.table_name,
+                            TableEntry::Loaded(Box::new(data_table)),
+                        );
+                        remove_candidate(&recovery.candidate.path)?;
+                    }
+                    // 破棄
+                    KeyCode::Char('d') => {
+                        let recovery = recoveries.remove(selected);
+                        remove_candidate(&recovery.candidate.path)?;
+                    }
+                    // 残りは次回起動時にも確認する
+                    KeyCode::Esc => return Ok(ConsoleState::Select(None)),
+                    _ => {}
                 }
             }
-            let data_table = DataTable::new(data);
-            let fname = path.file_name().unwrap().to_os_string();
-            data_tables.insert(fname, data_table);
         }
+    }
+    /// 全テーブルを読み込んで検査し、整合性チェックの画面の項目を作る
+    fn check_tables<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Vec<Finding>> {
+        let table_names: Vec<String> = self
+            .data_tables
+            .keys()
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        let failed = |table_name: &str, reason: String| Finding {
+            table_name: table_name.to_string(),
+            cell: None,
+            label: ListItem::new(format!("{}  {}", table_name, reason))
+                .style(Style::default().fg(Color::Red)),
+        };
+        let mut findings = Vec::new();
+        for table_name in table_names {
+            if self.get_table(&table_name).is_none() {
+                terminal.draw(|f| ui::loading(f, &table_name))?;
+            }
+            if !self.load_table(&table_name) {
+                let reason = match self.data_tables.get(&OsString::from(&table_name)) {
+                    Some(TableEntry::Failed(_, e)) => format!("読み込めません: {}", e),
+                    _ => "読み込めません".to_string(),
+                };
+                findings.push(failed(&table_name, reason));
+                continue;
+            }
+            let data_table = self.get_table(&table_name).unwrap();
+            let violations = match validate_table(data_table) {
+                Ok(violations) => violations,
+                Err(e) => {
+                    findings.push(failed(&table_name, format!("読み出せません: {:#}", e)));
+                    continue;
+                }
+            };
+            let total = violations.len();
+            for violation in violations.into_iter().take(MAX_FINDINGS_PER_TABLE) {
+                let column = &data_table.schema.columns[violation.column].name;
+                findings.push(Finding {
+                    table_name: table_name.clone(),
+                    cell: Some((violation.row, violation.column)),
+                    label: ListItem::new(format!(
+                        "{}  行 {}  {}: {}",
+                        table_name, violation.row, column, violation.message
+                    )),
+                });
+            }
+            if total > MAX_FINDINGS_PER_TABLE {
+                findings.push(Finding {
+                    table_name: table_name.clone(),
+                    cell: None,
+                    label: ListItem::new(format!(
+                        "{}  ほか {} 件",
+                        table_name,
+                        total - MAX_FINDINGS_PER_TABLE
+                    ))
+                    .style(Style::default().fg(Color::DarkGray)),
+                });
+            }
+        }
+        Ok(findings)
+    }
+    /// 整合性チェックの結果を表示し、選んだ違反のセルをテーブル編集で開く
+    fn check_integrity<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<ConsoleState> {
+        let mut findings = self.check_tables(terminal)?;
+        let mut list = StatefulList::with_items(Vec::new());
 
-        Self {
-            state: ConsoleState::Start,
-            data_tables,
-            master_dir: master_dir.to_string(),
-            archive_dir,
+        loop {
+            let count = findings.iter().filter(|f| f.cell.is_some()).count();
+            let title = format!("整合性チェック（{} 件）", count);
+            list.items = match findings.is_empty() {
+                true => vec![ListItem::new("型や規則に合わない値はありません")],
+                false => findings.iter().map(|f| f.label.clone()).collect(),
+            };
+            if list.state.selected().is_none_or(|i| i >= list.items.len()) {
+                list.state.select(Some(0));
+            }
+
+            self.autosave_if_due()?;
+            terminal.draw(|f| ui::integrity(f, &mut list, &title))?;
+            if let Some(Event::Key(key)) = poll_event()? {
+                match key.code {
+                    KeyCode::Down => list.next(),
+                    KeyCode::Up => list.previous(),
+                    // 編集した後などに検査し直す
+                    KeyCode::Char('r') => findings = self.check_tables(terminal)?,
+                    // 違反のあるセルを選択した状態でテーブル編集に移る
+                    KeyCode::Enter => {
+                        let Some(finding) = list.state.selected().and_then(|i| findings.get(i))
+                        else {
+                            continue;
+                        };
+                        let Some((row, column)) = finding.cell else {
+                            continue;
+                        };
+                        let table_name = finding.table_name.clone();
+                        if let Some(message) = self.lock_table(&table_name) {
+                            ask(
+                                terminal,
+                                |f| ui::integrity(f, &mut list, &title),
+                                "ロック",
+                                &[message],
+                                &[(KeyCode::Enter, "OK")],
+                            )?;
+                        }
+                        let data_table = self.get_table_mut(&table_name).unwrap();
+                        data_table.state.select(Some(row));
+                        data_table.selected_column = Some(column);
+                        return Ok(ConsoleState::EditTable(table_name));
+                    }
+                    KeyCode::Esc => return Ok(ConsoleState::Select(None)),
+                    _ => {}
+                }
+            }
         }
     }
+    /// 履歴のスナップショットを新しい順に表示し、テーブルの閲覧・マスタへの復元を行う画面
+    fn history<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<ConsoleState> {
+        let mut status = std::mem::take(&mut self.status);
+        // 一覧を読めなくても画面は開き、理由を表示する
+        let snapshots = match self.archive.snapshots() {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                status = format!("couldn't list the history: {:#}", e);
+                Vec::new()
+            }
+        };
+        let snapshot_labels = |marked: Option<usize>| -> Vec<ListItem<'static>> {
+            snapshots
+                .iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    let mark = if marked == Some(i) { "◆ " } else { "  " };
+                    let author = match &s.info {
+                        Some(info) => {
+                            let author = match info.host.is_empty() {
+                                true => info.user.clone(),
+                                false => format!("{}@{}", info.user, info.host),
+                            };
+                            match info.message.is_empty() {
+                                true => format!("  {}", author),
+                                false => format!("  {}  \"{}\"", author, info.message),
+                            }
+                        }
+                        None => String::new(),
+                    };
+                    if let Some(reason) = &s.unreadable {
+                        return ListItem::new(format!(
+                            "{}{}  (読めないマニフェスト: {})",
+                            mark, s.name, reason
+                        ));
+                    }
+                    ListItem::new(format!(
+                        "{}{}{}  ({} changed / {} tables, {})",
+                        mark,
+                        s.name,
+                        author,
+                        s.changed_count(),
+                        s.tables.len(),
+                        format_size(s.logical_size())
+                    ))
+                })
+                .collect()
+        };
+        // 差分の比較元として印を付けたスナップショット
+        let mut marked: Option<usize> = None;
+        let mut snapshot_list = StatefulList::with_items(snapshot_labels(marked));
+        snapshot_list.next();
+        let mut table_list = StatefulList::with_items(Vec::new());
+        let mut focus_tables = false;
+        if status.is_empty() && snapshots.is_empty() {
+            status = "no snapshots yet".to_string();
+        }
+
+        loop {
+            self.autosave_if_due()?;
+            let snapshot = snapshot_list.state.selected().map(|i| &snapshots[i]);
+            table_list.items = snapshot
+                .map(|s| {
+                    // このスナップショットで変更されたテーブルに * を付ける
+                    s.tables
+                        .iter()
+                        .map(|t| match s.is_changed(t) {
+                            true => {
+                                let counts = match s.blobs.get(t).and_then(|b| b.counts) {
+                                    Some((added, removed, changed)) => {
+                                        format!("  +{} -{} ~{}", added, removed, changed)
+                                    }
+                                    None => String::new(),
+                                };
+                                ListItem::new(format!("* {}{}", t.to_string_lossy(), counts))
+                                    .style(Style::default().fg(Color::Yellow))
+                            }
+                            false => ListItem::new(format!("  {}", t.to_string_lossy())),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            if table_list.items.is_empty() {
+                focus_tables = false;
+            }
+            let table_name = table_list
+                .state
+                .selected()
+                .and_then(|i| snapshot.and_then(|s| s.tables.get(i)));
 
+            terminal.draw(|f| {
+                ui::history(
+                    f,
+                    &mut snapshot_list,
+                    &mut table_list,
+                    focus_tables,
+                    &status,
+                )
+            })?;
+            let Some(Event::Key(key)) = poll_event()? else {
+                continue;
+            };
+            match key.code {
+                KeyCode::Esc | KeyCode::Left | KeyCode::BackTab if focus_tables => {
+                    focus_tables = false
+                }
+                KeyCode::Esc => return Ok(ConsoleState::Select(None)),
+                KeyCode::Down if focus_tables => table_list.next(),
+                KeyCode::Up if focus_tables => table_list.previous(),
+                KeyCode::Down | KeyCode::Up => {
+                    match key.code {
+                        KeyCode::Down => snapshot_list.next(),
+                        _ => snapshot_list.previous(),
+                    }
+                    table_list.state.select(Some(0));
+                }
+                KeyCode::Enter | KeyCode::Right | KeyCode::Tab if !focus_tables => {
+                    focus_tables = !table_list.items.is_empty();
+                    table_list.state.select(Some(0));
+                }
+                // 規則に当てはまらないスナップショットを確認してから消す
+                KeyCode::Char('p') => {
+                    if !self.archive.can_prune() {
+                        status = format!("the {} history can't be pruned", self.archive.name());
+                        continue;
+                    }
+                    if !self.retention.is_enabled() {
+                        status = "no retention rules are configured".to_string();
+                        continue;
+                    }
+                    let plan = self.retention.plan(&snapshots, Local::now().date_naive());
+                    if plan.is_empty() {
+                        status = "nothing to prune".to_string();
+                        continue;
+                    }
+                    // 残すスナップショットと共有している内容は空かないので数えない
+                    let total = freed_size(&snapshots, &plan);
+                    let mut lines = vec![format!(
+                        "{} 件を削除します ({} 空きます)",
+                        plan.len(),
+                        format_size(total)
+                    )];
+                    lines.extend(
+                        plan.iter()
+                            .take(20)
+                            .map(|s| format!("  {}  ({})", s.name, format_size(s.size))),
+                    );
+                    if plan.len() > 20 {
+                        lines.push(format!("  ... 他 {} 件", plan.len() - 20));
+                    }
+                    let choice = ask(
+                        terminal,
+                        |f| {
+                            ui::history(
+                                f,
+                                &mut snapshot_list,
+                                &mut table_list,
+                                focus_tables,
+                                &status,
+                            )
+                        },
+                        "履歴の整理",
+                        &lines,
+                        &[
+                            (KeyCode::Char('y'), "削除する"),
+                            (KeyCode::Esc, "キャンセル"),
+                        ],
+                    )?;
+                    if choice == KeyCode::Char('y') {
+                        match self.archive.prune(&plan) {
+                            // 一覧を読み直す
+                            Ok(count) => {
+                                self.status = format!("pruned {} snapshots", count);
+                                return Ok(ConsoleState::History);
+                            }
+                            Err(e) => status = format!("prune failed: {:#}", e),
+                        }
+                    }
+                }
+                // CSVのフォルダとして書き出す
+                KeyCode::Char('x') if !focus_tables => {
+                    let Some(snapshot) = snapshot else {
+                        continue;
+                    };
+                    let dest = self.export_dir.join(&snapshot.name);
+                    status = match self.archive.export(snapshot, &dest) {
+                        Ok(()) => format!("exported to {}", dest.display()),
+                        Err(e) => format!("export failed: {:#}", e),
+                    };
+                }
+                // 比較元として印を付ける
+                KeyCode::Char('m') if !focus_tables => {
+                    let selected = snapshot_list.state.selected();
+                    marked = if marked == selected { None } else { selected };
+                    snapshot_list.items = snapshot_labels(marked);
+                }
+                // 印を付けたスナップショット、なければそのテーブルの1つ前の版との差分
+                KeyCode::Char('d') if focus_tables => {
+                    let (Some(i), Some(table_name)) = (snapshot_list.state.selected(), table_name)
+                    else {
+                        continue;
+                    };
+                    let base = match marked.filter(|m| *m != i) {
+                        Some(m) => Some(&snapshots[m]),
+                        None => snapshots[i + 1..]
+                            .iter()
+                            .find(|s| s.tables.contains(table_name)),
+                    };
+                    let versions = base
+                        .map(|base| {
+                            let path = self.archive.table_path(base, table_name)?;
+                            Ok::<_, anyhow::Error>(load_version(&path, base.name.clone()))
+                        })
+                        .transpose()
+                        .and_then(|old| {
+                            let snapshot = &snapshots[i];
+                            let path = self.archive.table_path(snapshot, table_name)?;
+                            Ok((old, load_version(&path, snapshot.name.clone())))
+                        });
+                    let (old, new) = match versions {
+                        Ok(versions) => versions,
+                        Err(e) => {
+                            status = format!("{}: {:#}", table_name.to_string_lossy(), e);
+                            continue;
+                        }
+                    };
+                    let old = old.unwrap_or_else(|| Version {
+                        label: "(なし)".to_string(),
+                        ..Version::default()
+                    });
+                    self.diff_view(terminal, table_name, old, new)?;
+                }
+                // 読み取り専用で閲覧する
+                KeyCode::Enter => {
+                    let (Some(snapshot), Some(table_name)) = (snapshot, table_name) else {
+                        continue;
+                    };
+                    if let Err(e) = self.view_snapshot_table(terminal, snapshot, table_name) {
+                        status = format!("{}: {:#}", table_name.to_string_lossy(), e);
+                    }
+                }
+                // テーブルにフォーカスがあればそのテーブルだけ、なければスナップショット全体を復元する
+                KeyCode::Char('r') => {
+                    let Some(snapshot) = snapshot else {
+                        continue;
+                    };
+                    let tables = match focus_tables {
+                        true => table_name.into_iter().cloned().collect(),
+                        false => snapshot.tables.clone(),
+                    };
+                    let mut lines = vec![format!("{} からマスタに書き戻します", snapshot.name)];
+                    let unarchived = self.unarchived_changes(&tables).unwrap_or_default();
+                    for table_name in tables.iter() {
+                        let dirty = self
+                            .get_table(table_name.clone())
+                            .is_some_and(DataTable::is_modified);
+                        lines.push(match dirty {
+                            true => format!(
+                                "* {}  (未保存の変更は破棄されます)",
+                                table_name.to_string_lossy()
+                            ),
+                            false => format!("  {}", table_name.to_string_lossy()),
+                        });
+                        if unarchived.contains(table_name) {
+                            lines.push(
+                                "    マスタに履歴にない変更があります。書き戻す前に履歴に残します"
+                                    .to_string(),
+                            );
+                        }
+                    }
+                    let choice = ask(
+                        terminal,
+                        |f| {
+                            ui::history(
+                                f,
+                                &mut snapshot_list,
+                                &mut table_list,
+                                focus_tables,
+                                &status,
+                            )
+                        },
+                        "スナップショットの復元",
+                        &lines,
+                        &[
+                            (KeyCode::Char('y'), "復元する"),
+                            (KeyCode::Esc, "キャンセル"),
+                        ],
+                    )?;
+                    if choice != KeyCode::Char('y') {
+                        continue;
+                    }
+                    match self.restore_tables(snapshot, &tables) {
+                        // 復元前後のスナップショットが増えているので一覧を読み直す
+                        Ok(()) => {
+                            self.status =
+                                format!("restored {} tables from {}", tables.len(), snapshot.name);
+                            return Ok(ConsoleState::History);
+                        }
+                        Err(e) => status = format!("restore failed: {:#}", e),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    /// スナップショットのテーブルを読み取り専用で表示する
+    fn view_snapshot_table<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        snapshot: &Snapshot,
+        table_name: &OsString,
+    ) -> Result<()> {
+        let mut data_table = DataTable::load(&self.archive.table_path(snapshot, table_name)?)?;
+        data_table.set_null_tokens(self.nulls.clone());
+        data_table.read_only = Some(format!(
+            "履歴 {} の {}",
+            snapshot.name,
+            table_name.to_string_lossy()
+        ));
+        let display = self.display.clone();
+        loop {
+            self.autosave_if_due()?;
+            terminal.draw(|f| ui::edit(f, &mut data_table, &display, ""))?;
+            if let Some(Event::Key(key)) = poll_event()? {
+                match key.code {
+                    KeyCode::Esc => return Ok(()),
+                    KeyCode::Down if !data_table.rows.is_empty() => data_table.next(),
+                    KeyCode::Up => data_table.previous(),
+                    _ => {}
+                }
+            }
+        }
+    }
+    /// マスタファイルに履歴に残っていない変更があるテーブル
+    /// 読み込んでから他で変更されたもの（保存時と同じ判定）と、最新のスナップショットと内容が違うものが当てはまる
+    fn unarchived_changes(&self, tables: &[OsString]) -> Result<Vec<OsString>> {
+        let latest = self.archive.snapshots()?.into_iter().next();
+        let mut changed = Vec::new();
+        for table_name in tables.iter() {
+            let path = Path::new(&self.master_dir).join(table_name);
+            let Ok(current) = file_hash(&path) else {
+                continue;
+            };
+            let since_loaded = self
+                .get_table(table_name.clone())
+                .and_then(|t| t.origin.as_ref())
+                .is_some_and(|origin| origin.is_changed(&path));
+            let archived = latest
+                .as_ref()
+                .filter(|snapshot| snapshot.tables.contains(table_name))
+                .and_then(|snapshot| self.archive.table_path(snapshot, table_name).ok())
+                .and_then(|archived| file_hash(&archived).ok())
+                .is_some_and(|hash| hash == current);
+            if since_loaded || !archived {
+                changed.push(table_name.clone());
+            }
+        }
+        Ok(changed)
+    }
+    /// 2つの版の間で追加・削除・変更された行の数
+    fn version_counts(
+        &self,
+        table_name: &OsString,
+        old: &Version,
+        new: &Version,
+    ) -> (usize, usize, usize) {
+        let key = self.diff_key(table_name);
+        diff_tables_by(&old.header, &old.rows, &new.header, &new.rows, key).counts()
+    }
+    /// スナップショットのテーブルをマスタに書き戻す
+    /// 他で編集中のテーブルがあれば何も書き込まない。読み込み済みのテーブルは編集内容を捨てて読み直す
+    /// 履歴に残っていない変更があるマスタファイルは、先にその内容を履歴に残してから書き戻す
+    /// 書き戻した内容は保存と同じく履歴に残す
+    fn restore_tables(&mut self, snapshot: &Snapshot, tables: &[OsString]) -> Result<()> {
+        for table_name in tables.iter() {
+            let name = table_name.to_string_lossy().into_owned();
+            self.lock_table(&name);
+            if !self.locks.contains_key(table_name) {
+                anyhow::bail!("{} is being edited elsewhere", name);
+            }
+        }
+        let all_tables: Vec<OsString> = self.data_tables.keys().cloned().collect();
+
+        let unarchived = self.unarchived_changes(tables)?;
+        if !unarchived.is_empty() {
+            let latest = self.archive.snapshots()?.into_iter().next();
+            let mut contents = Vec::new();
+            for table_name in unarchived.iter() {
+                let path = Path::new(&self.master_dir).join(table_name);
+                let bytes = get_bytes(&path)?;
+                let old = match &latest {
+                    Some(latest) => {
+                        load_version(&self.archive.table_path(latest, table_name)?, String::new())
+                    }
+                    None => Version::default(),
+                };
+                let counts =
+                    self.version_counts(table_name, &old, &load_version(&path, String::new()));
+                contents.push((table_name, bytes, counts));
+            }
+            let changes: Vec<TableChange> = contents
+                .iter()
+                .map(|(table_name, bytes, counts)| TableChange {
+                    table_name,
+                    bytes,
+                    counts: *counts,
+                })
+                .collect();
+            // マスタファイルはすでにその内容なので、書き込んだものとして記録する
+            let info = SnapshotInfo::current(&format!("{} から復元する前の内容", snapshot.name));
+            self.archive.before_write(&changes, &all_tables, &info)?;
+            self.archive
+                .after_write(&changes, &info, true)
+                .context("couldn't record the changes made outside before restoring")?;
+        }
+
+        let mut contents = Vec::new();
+        for table_name in tables.iter() {
+            let snapshot_path = self.archive.table_path(snapshot, table_name)?;
+            let bytes = get_bytes(&snapshot_path)
+                .with_context(|| format!("{}", table_name.to_string_lossy()))?;
+            let path = Path::new(&self.master_dir).join(table_name);
+            let counts = self.version_counts(
+                table_name,
+                &load_version(&path, String::new()),
+                &load_version(&snapshot_path, String::new()),
+            );
+            contents.push((table_name, bytes, counts));
+        }
+        // 保存と同じく、履歴に残してからマスタをまとめて置き換える
+        let changes: Vec<TableChange> = contents
+            .iter()
+            .map(|(table_name, bytes, counts)| TableChange {
+                table_name,
+                bytes,
+                counts: *counts,
+            })
+            .collect();
+        let info = SnapshotInfo::current(&format!("{} から復元", snapshot.name));
+        self.archive.before_write(&changes, &all_tables, &info)?;
+        let files: Vec<(PathBuf, Vec<u8>)> = contents
+            .iter()
+            .map(|(table_name, bytes, _)| {
+                (Path::new(&self.master_dir).join(table_name), bytes.clone())
+            })
+            .collect();
+        let result = save_files_atomically(&files);
+        let archived = self.archive.after_write(&changes, &info, result.is_ok());
+        for (table_name, (path, _)) in tables.iter().zip(files) {
+            match result.is_ok() {
+                true => {
+                    self.clear_swap(table_name)?;
+                    self.data_tables
+                        .insert(table_name.clone(), TableEntry::Unloaded(path));
+                    self.locks.remove(table_name);
+                }
+                false => self.unlock_if_idle(&table_name.to_string_lossy()),
+            }
+        }
+        result?;
+        archived.context("restored to master, but couldn't record the history")
+    }
+    /// 差分で行を対応付けるキー列
+    fn diff_key(&self, table_name: &OsString) -> Option<&str> {
+        self.diff_keys.get(table_name).map(String::as_str)
+    }
+    /// 2つの版の差分を表示する
+    fn diff_view<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        table_name: &OsString,
+        old: Version,
+        new: Version,
+    ) -> Result<()> {
+        let key = self.diff_key(table_name);
+        let diff = diff_tables_by(&old.header, &old.rows, &new.header, &new.rows, key);
+        let (added, removed, changed) = diff.counts();
+        let matched_by = match key {
+            Some(key) if diff.keyed => format!("キー: {}", key),
+            _ => "行の並び".to_string(),
+        };
+        let mut layout = ui::DiffLayout::SideBySide;
+        let mut show_same = false;
+        let mut scroll: (u16, u16) = (0, 0);
+        let mut lines = ui::diff_view_lines(&diff, &old, &new, layout, show_same);
+        loop {
+            self.autosave_if_due()?;
+            let hint = format!(
+                "+{} -{} ~{}  ({}で対応付け)  t: 表示形式  a: 変更のない行  ↑↓←→/PgUp/PgDn: スクロール  Esc: 戻る",
+                added, removed, changed, matched_by
+            );
+            terminal.draw(|f| ui::diff_view(f, &old, &new, &lines, scroll, &hint))?;
+            let Some(Event::Key(key)) = poll_event()? else {
+                continue;
+            };
+            match key.code {
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Char('t') => {
+                    layout = match layout {
+                        ui::DiffLayout::SideBySide => ui::DiffLayout::Unified,
+                        ui::DiffLayout::Unified => ui::DiffLayout::SideBySide,
+                    };
+                    lines = ui::diff_view_lines(&diff, &old, &new, layout, show_same);
+                }
+                KeyCode::Char('a') => {
+                    show_same = !show_same;
+                    scroll.0 = 0;
+                    lines = ui::diff_view_lines(&diff, &old, &new, layout, show_same);
+                }
+                KeyCode::Down => scroll.0 = scroll.0.saturating_add(1),
+                KeyCode::Up => scroll.0 = scroll.0.saturating_sub(1),
+                KeyCode::PageDown => scroll.0 = scroll.0.saturating_add(20),
+                KeyCode::PageUp => scroll.0 = scroll.0.saturating_sub(20),
+                KeyCode::Right => scroll.1 = scroll.1.saturating_add(10),
+                KeyCode::Left => scroll.1 = scroll.1.saturating_sub(10),
+                _ => {}
+            }
+        }
+    }
     fn get_table(&self, table_name: impl Into<OsString>) -> Option<&DataTable> {
         let key = table_name.into();
-        self.data_tables.get(&key)
+        self.data_tables.get(&key).and_then(TableEntry::table)
     }
     fn get_table_mut(&mut self, table_name: impl Into<OsString>) -> Option<&mut DataTable> {
         let key = table_name.into();
-        self.data_tables.get_mut(&key)
+        self.data_tables
+            .get_mut(&key)
+            .and_then(TableEntry::table_mut)
+    }
+    /// 未読込・読込失敗のテーブルを読み込み、読み込めたかを返す
+    /// 失敗した場合は理由をエントリに残す
+    fn load_table(&mut self, table_name: impl Into<OsString>) -> bool {
+        let key = table_name.into();
+        if let Some(entry) = self.data_tables.get_mut(&key) {
+            match entry {
+                TableEntry::Loaded(_) => {}
+                TableEntry::Unloaded(path) | TableEntry::Failed(path, _) => {
+                    let path = path.clone();
+                    *entry = match DataTable::load(&path) {
+                        Ok(mut data_table) => {
+                            data_table.set_null_tokens(self.nulls.clone());
+                            TableEntry::Loaded(Box::new(data_table))
+                        }
+                        Err(e) => TableEntry::Failed(path, e),
+                    };
+                }
+            }
+        }
+        self.get_table(key).is_some()
+    }
+    /// 読み込み中の表示をしてからテーブルを読み込む
+    fn load_table_with_indicator<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        menu_list: &mut StatefulList<ListItem>,
+        table_name: &str,
+    ) -> Result<bool> {
+        if self.get_table(table_name).is_none() {
+            terminal.draw(|f| {
+                ui::select(f, menu_list, "");
+                ui::loading(f, table_name);
+            })?;
+        }
+        Ok(self.load_table(table_name))
     }
-    fn get_text_for_save(self, table_name: String) -> Option<String> {
+    fn get_text_for_save(self, table_name: String) -> Result<Option<String>> {
         let v = self.get_table(table_name);
-        v.map(|data_table| data_table.text())
+        v.map(|data_table| data_table.text()).transpose()
     }
     pub fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<()> {
         // 画面遷移のイベントループ
         loop {
             self.state = match self.state.clone() {
                 ConsoleState::Start => ConsoleState::Select(None),
+                ConsoleState::Recover => self.recover(terminal)?,
+                ConsoleState::History => self.history(terminal)?,
                 ConsoleState::Select(name) => self.select_csv(terminal, name)?,
                 ConsoleState::EditTable(name) => self.table_editing(terminal, name)?,
                 ConsoleState::EditRow(table_name) => self.row_editing(terminal, table_name)?,
-                ConsoleState::CheckIntegrity => {
-                    println!("Integrity check mode");
-                    ConsoleState::Select(None)
+                ConsoleState::EditHeader(table_name) => {
+                    self.header_editing(terminal, table_name)?
                 }
+                ConsoleState::CheckIntegrity => self.check_integrity(terminal)?,
                 ConsoleState::Quit => break,
             };
         }
+        self.locks.clear();
+        // 正常終了時はこのプロセスのスワップファイルを残さない
+        // 復元を後回しにしたものや、他のプロセスのものはそのまま残す
+        if self.swap_dir.is_dir() {
+            std::fs::remove_dir_all(&self.swap_dir)?;
+        }
         Ok(())
     }
 
+    /// テーブル一覧に表示するラベル
+    fn table_labels(&self) -> Vec<ListItem<'static>> {
+        self.data_tables
+            .iter()
+            .map(|(name, entry)| {
+                let name = name.to_string_lossy();
+                match entry {
+                    // 未保存の変更があるテーブルは * を付ける
+                    TableEntry::Loaded(data_table) if data_table.is_modified() => {
+                        ListItem::new(format!(
+                            "* {}  [{} / {} / {}]  ({} changes)",
+                            name,
+                            data_table.dialect,
+                            data_table.encoding,
+                            data_table.dialect.terminator,
+                            data_table.change_count
+                        ))
+                        .style(Style::default().fg(Color::Yellow))
+                    }
+                    TableEntry::Loaded(data_table) => ListItem::new(format!(
+                        "  {}  [{} / {} / {}]",
+                        name,
+                        data_table.dialect,
+                        data_table.encoding,
+                        data_table.dialect.terminator
+                    )),
+                    TableEntry::Unloaded(_) => ListItem::new(format!("  {}", name)),
+                    TableEntry::Failed(_, e) => {
+                        ListItem::new(format!("  {}  failed to load: {}", name, e))
+                            .style(Style::default().fg(Color::Red))
+                    }
+                }
+            })
+            .collect()
+    }
     fn select_csv<B: Backend>(
-        &self,
+        &mut self,
         terminal: &mut Terminal<B>,
         table_name: Option<String>,
     ) -> Result<ConsoleState> {
@@ -121,8 +1616,8 @@ impl App {
             .cloned()
             .map(|os_string| os_string.into_string().unwrap())
             .collect();
-        let mut menu_list =
-            StatefulList::with_items(items.iter().cloned().map(ListItem::new).collect());
+        let mut menu_list = StatefulList::with_items(self.table_labels());
+        let mut status = String::new();
         match table_name {
             Some(t) => {
                 let idx = items.iter().position(|x| *x == t);
@@ -132,33 +1627,176 @@ impl App {
         }
 
         loop {
-            terminal.draw(|f| ui::select(f, &mut menu_list))?;
-            if let Event::Key(key) = event::read()? {
+            self.autosave_if_due()?;
+            terminal.draw(|f| ui::select(f, &mut menu_list, &status))?;
+            if let Some(Event::Key(key)) = poll_event()? {
                 match (key.code, key.modifiers) {
                     // 編集
                     (KeyCode::Enter, _) => {
-                        let selected = menu_list.state.selected().unwrap();
+                        let Some(selected) = menu_list.state.selected() else {
+                            continue;
+                        };
                         let selected_table_name = &items[selected];
-                        return Ok(ConsoleState::EditTable(selected_table_name.to_string()));
+                        if self.load_table_with_indicator(
+                            terminal,
+                            &mut menu_list,
+                            selected_table_name,
+                        )? {
+                            if let Some(message) = self.lock_table(selected_table_name) {
+                                ask(
+                                    terminal,
+                                    |f| ui::select(f, &mut menu_list, &status),
+                                    "ロック",
+                                    &[message],
+                                    &[(KeyCode::Enter, "OK")],
+                                )?;
+                            }
+                            return Ok(ConsoleState::EditTable(selected_table_name.to_string()));
+                        }
+                        status = format!("{}: failed to load", selected_table_name);
+                        menu_list.items = self.table_labels();
                     }
                     // プログラム終了
-                    (KeyCode::Char('q'), _) => return Ok(ConsoleState::Quit),
+                    // 未保存のテーブルがあれば確認する
+                    (KeyCode::Char('q'), _) => {
+                        let modified = self.modified_table_names();
+                        if modified.is_empty() {
+                            return Ok(ConsoleState::Quit);
+                        }
+                        let lines: Vec<String> = modified
+                            .iter()
+                            .map(|name| format!("* {}", name.to_string_lossy()))
+                            .collect();
+                        let choice = ask(
+                            terminal,
+                            |f| ui::select(f, &mut menu_list, &status),
+                            "未保存のテーブルがあります",
+                            &lines,
+                            &[
+                                (KeyCode::Char('s'), "すべて保存して終了"),
+                                (KeyCode::Char('d'), "破棄して終了"),
+                                (KeyCode::Esc, "キャンセル"),
+                            ],
+                        )?;
+                        match choice {
+                            KeyCode::Char('s') => {
+                                let message = match self.resolve_conflicts(terminal)? {
+                                    true => prompt(
+                                        terminal,
+                                        |f| ui::select(f, &mut menu_list, &status),
+                                        "保存メッセージ",
+                                        "Enter: 保存（空欄なら省略）  Esc: キャンセル",
+                                    )?,
+                                    false => None,
+                                };
+                                let Some(message) = message else {
+                                    status = "save cancelled".to_string();
+                                    menu_list.items = self.table_labels();
+                                    continue;
+                                };
+                                match self.save_modified(&message) {
+                                    Ok(_) => {
+                                        // 整理できなくても保存は済んでいるので終了する
+                                        let _ = self.prune_history();
+                                        return Ok(ConsoleState::Quit);
+                                    }
+                                    Err(e) => {
+                                        status = format!("save failed: {:#}", e);
+                                        menu_list.items = self.table_labels();
+                                    }
+                                }
+                            }
+                            KeyCode::Char('d') => return Ok(ConsoleState::Quit),
+                            _ => {}
+                        }
+                    }
+                    // 履歴の閲覧
+                    (KeyCode::Char('h'), _) => return Ok(ConsoleState::History),
+                    // 整合性チェック
+                    (KeyCode::Char('c'), _) => return Ok(ConsoleState::CheckIntegrity),
+                    // 編集中の内容とマスタファイルの差分
+                    (KeyCode::Char('d'), _) => {
+                        let Some(selected) = menu_list.state.selected() else {
+                            continue;
+                        };
+                        let table_name = OsString::from(&items[selected]);
+                        let Some(data_table) = self.get_table(table_name.clone()) else {
+                            status = format!("{}: not opened yet", items[selected]);
+                            continue;
+                        };
+                        let new = Version {
+                            label: "編集中".to_string(),
+                            header: data_table.header(),
+                            rows: data_table.to_rows()?,
+                        };
+                        let path = Path::new(&self.master_dir).join(&table_name);
+                        let old = load_version(&path, "マスタ".to_string());
+                        self.diff_view(terminal, &table_name, old, new)?;
+                    }
                     // 移動
                     (KeyCode::Down, _) => menu_list.next(),
                     (KeyCode::Up, _) => menu_list.previous(),
+                    // 文字コードの変換
+                    (KeyCode::Char('e'), _) => {
+                        let Some(selected) = menu_list.state.selected() else {
+                            continue;
+                        };
+                        let selected_table_name = items[selected].clone();
+                        if !self.load_table_with_indicator(
+                            terminal,
+                            &mut menu_list,
+                            &selected_table_name,
+                        )? {
+                            status = format!("{}: failed to load", selected_table_name);
+                            menu_list.items = self.table_labels();
+                            continue;
+                        }
+                        self.lock_table(&selected_table_name);
+                        let data_table = self.get_table_mut(selected_table_name.clone()).unwrap();
+                        if let Some(reason) = data_table.read_only.clone() {
+                            status = format!("{}: {}", selected_table_name, reason);
+                            continue;
+                        }
+                        let encoding = data_table.encoding.next();
+                        status = match data_table.convert_encoding(encoding) {
+                            Ok(()) => format!("{}: converted to {}", selected_table_name, encoding),
+                            Err(e) => format!("{}: {}", selected_table_name, e),
+                        };
+                        menu_list.items = self.table_labels();
+                    }
                     // 編集したテーブルを保存
-                    // TODO: 未保存のテーブルがあるときだけ発動するように
+                    // 読み込んでから他で変更されたテーブルがあれば、先にどうするかを選ぶ
                     (KeyCode::Char('s'), KeyModifiers::CONTROL) => {
-                        let keys = self.data_tables.keys();
-                        let now_str = Local::now().format("%Y-%m-%d-%H%M%S-%Z").to_string();
-                        let save_dir = self.archive_dir.clone() + &now_str;
-
-                        for table_name in keys {
-                            let save_root_dir = OsString::from(&save_dir);
-                            let save_path = Path::new(&save_root_dir).join(Path::new(table_name));
-                            save_to_file(self.get_table(table_name).unwrap().text(), save_path)?;
+                        if self.modified_table_names().is_empty() {
+                            status = "no changes to save".to_string();
+                            continue;
                         }
-                        copy_recursive(save_dir, &self.master_dir)?;
+                        let message = match self.resolve_conflicts(terminal)? {
+                            true => prompt(
+                                terminal,
+                                |f| ui::select(f, &mut menu_list, &status),
+                                "保存メッセージ",
+                                "Enter: 保存（空欄なら省略）  Esc: キャンセル",
+                            )?,
+                            false => None,
+                        };
+                        let Some(message) = message else {
+                            status = "save cancelled".to_string();
+                            menu_list.items = self.table_labels();
+                            continue;
+                        };
+                        status = match self.save_modified(&message) {
+                            Ok(0) => "no changes to save".to_string(),
+                            Ok(n) => match self.prune_history() {
+                                Ok(0) => format!("saved {} tables", n),
+                                Ok(pruned) => {
+                                    format!("saved {} tables, pruned {} snapshots", n, pruned)
+                                }
+                                Err(e) => format!("saved {} tables, prune failed: {:#}", n, e),
+                            },
+                            Err(e) => format!("save failed: {:#}", e),
+                        };
+                        menu_list.items = self.table_labels();
                     }
                     _ => {}
                 }
@@ -171,26 +1809,58 @@ impl App {
         terminal: &mut Terminal<B>,
         fname: String,
     ) -> Result<ConsoleState> {
+        let display = self.display.clone();
+        let mut status = std::mem::take(&mut self.status);
         loop {
+            self.autosave_if_due()?;
             let table_name = fname.clone();
             let data_table = self.get_table_mut(table_name.clone()).unwrap();
 
-            terminal.draw(|f| ui::edit(f, data_table))?;
+            terminal.draw(|f| ui::edit(f, data_table, &display, &status))?;
 
-            if let Event::Key(key_event) = event::read()? {
+            if let Some(Event::Key(key_event)) = poll_event()? {
+                status.clear();
                 match key_event {
                     KeyEvent {
                         code: KeyCode::Esc, ..
-                    } => return Ok(ConsoleState::Select(Some(table_name))),
+                    } => {
+                        self.unlock_if_idle(&table_name);
+                        return Ok(ConsoleState::Select(Some(table_name)));
+                    }
+                    // 読み取り専用のテーブルは編集せず、スキーマファイルも書き出さない
+                    KeyEvent {
+                        code:
+                            KeyCode::Enter | KeyCode::Char('h') | KeyCode::Char('w') | KeyCode::Delete,
+                        ..
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Char('v'),
+                        modifiers: KeyModifiers::CONTROL,
+                        ..
+                    } if data_table.read_only.is_some() => {}
+                    // 空のテーブルで下に移動すると行が追加される
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        ..
+                    } if data_table.read_only.is_some() && data_table.rows.is_empty() => {}
                     KeyEvent {
                         code: KeyCode::Enter,
                         ..
                     } => match data_table.state.selected() {
+                        // 列がなければ先にヘッダを編集する
+                        _ if data_table.schema.columns.is_empty() => {
+                            return Ok(ConsoleState::EditHeader(table_name))
+                        }
                         Some(_) => return Ok(ConsoleState::EditRow(table_name)),
                         None => {
                             continue;
                         }
                     },
+                    // ヘッダの編集
+                    KeyEvent {
+                        code: KeyCode::Char('h'),
+                        ..
+                    } => return Ok(ConsoleState::EditHeader(table_name)),
                     KeyEvent {
                         code: KeyCode::Down,
                         ..
@@ -198,12 +1868,25 @@ impl App {
                     KeyEvent {
                         code: KeyCode::Up, ..
                     } => data_table.previous(),
+                    // 列の選択
+                    KeyEvent {
+                        code: KeyCode::Tab, ..
+                    } => data_table.next_column(),
+                    KeyEvent {
+                        code: KeyCode::BackTab,
+                        ..
+                    } => data_table.previous_column(),
                     // スキーマの再推論
                     KeyEvent {
                         code: KeyCode::Char('r'),
                         modifiers: KeyModifiers::CONTROL,
                         ..
                     } => data_table.infer_schema(Some(100)),
+                    // 現在のスキーマをスキーマファイルに書き出す
+                    KeyEvent {
+                        code: KeyCode::Char('w'),
+                        ..
+                    } => self.write_schema(terminal, &table_name)?,
                     // 行を選択
                     KeyEvent {
                         code: KeyCode::Right,
@@ -230,11 +1913,24 @@ impl App {
                         modifiers: KeyModifiers::CONTROL,
                         ..
                     } => {
-                        for idx in &data_table.rows_selected {
-                            let r = data_table.values[*idx].clone();
-                            data_table
-                                .values
-                                .insert(data_table.state.selected().unwrap() + 1, r)
+                        // 読めない行があれば何も貼り付けない
+                        let rows = data_table
+                            .rows_selected
+                            .iter()
+                            .map(|idx| data_table.rows.get(*idx).map(|r| r.into_owned()))
+                            .collect::<Result<Vec<_>>>();
+                        match rows {
+                            Ok(rows) => {
+                                for r in rows {
+                                    data_table
+                                        .rows
+                                        .insert(data_table.state.selected().unwrap() + 1, r)
+                                }
+                                if !data_table.rows_selected.is_empty() {
+                                    data_table.touch();
+                                }
+                            }
+                            Err(e) => status = format!("paste failed: {:#}", e),
                         }
                     }
                     //行削除
@@ -243,7 +1939,10 @@ impl App {
                         ..
                     } => {
                         for i in data_table.rows_selected.iter().rev() {
-                            data_table.values.remove(*i);
+                            data_table.rows.remove(*i);
+                        }
+                        if !data_table.rows_selected.is_empty() {
+                            data_table.touch();
                         }
                         data_table.rows_selected = BTreeSet::new(); //該当行を消したので初期化
                         data_table.state.select(None); // select行が消えた場合はNoneにする
@@ -254,6 +1953,50 @@ impl App {
         }
     }
 
+    /// テーブルの現在のスキーマを、宣言の雛形としてスキーマファイルに書き出す
+    /// 既にファイルがあれば上書きするか確認する。書き出した宣言はそのまま適用する
+    fn write_schema<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        table_name: &str,
+    ) -> Result<()> {
+        let csv_path = Path::new(&self.master_dir).join(table_name);
+        let path = sidecar_path(&csv_path);
+        let display = self.display.clone();
+        let data_table = self.get_table_mut(table_name).unwrap();
+        if path.exists() {
+            let choice = ask(
+                terminal,
+                |f| ui::edit(f, data_table, &display, ""),
+                "スキーマの書き出し",
+                &[format!("{} は既にあります。上書きしますか", path.display())],
+                &[
+                    (KeyCode::Char('y'), "上書きする"),
+                    (KeyCode::Esc, "キャンセル"),
+                ],
+            )?;
+            if choice != KeyCode::Char('y') {
+                return Ok(());
+            }
+        }
+        let specs = data_table.column_specs();
+        let message = match write_sidecar(&csv_path, &specs) {
+            Ok(path) => {
+                data_table.apply_declared(&specs);
+                format!("{} に書き出しました", path.display())
+            }
+            Err(e) => format!("書き出せませんでした: {:#}", e),
+        };
+        ask(
+            terminal,
+            |f| ui::edit(f, data_table, &display, ""),
+            "スキーマの書き出し",
+            &[message],
+            &[(KeyCode::Enter, "OK")],
+        )?;
+        Ok(())
+    }
+
     fn row_editing<B: Backend>(
         &mut self,
         terminal: &mut Terminal<B>,
@@ -281,21 +2024,43 @@ impl App {
                 .unwrap_or_else(|| Block::default().borders(Borders::ALL));
             textarea.set_block(b.style(Style::default()).title("アクティブ"));
         }
+        // 入力欄の内容を置き換える
+        fn replace(textarea: &mut TextArea<'_>, value: &str) {
+            let mut replaced = TextArea::from([value]);
+            inactivate(&mut replaced);
+            activate(&mut replaced);
+            replaced.move_cursor(tui_textarea::CursorMove::End);
+            *textarea = replaced;
+        }
 
         //表示するカラム名の作成
         let data_table = self.get_table_mut(table_name.clone()).unwrap();
-        let col_names = data_table
+        let columns: Vec<Column> = data_table.schema.columns.clone();
+        let nulls = data_table.nulls.clone();
+        let col_names: Vec<String> = data_table
             .schema
             .columns
             .iter()
-            .map(|c| format!("{}\n [{}]", c.name, c.data_type));
-        let mut which: usize = 0;
+            .map(|c| match &c.description {
+                Some(description) => format!("{}\n [{}]\n{}", c.name, c.data_type, description),
+                None => format!("{}\n [{}]", c.name, c.data_type),
+            })
+            .collect();
         let header_len: usize = col_names.len();
+        // 選択中の列の項目から編集を始める
+        let mut which: usize = data_table
+            .selected_column
+            .filter(|i| *i < header_len)
+            .unwrap_or(0);
 
-        let default_row_data = data_table
-            .values
-            .get(data_table.state.selected().unwrap())
-            .unwrap();
+        let selected = data_table.state.selected().unwrap();
+        let default_row_data = match data_table.rows.get(selected) {
+            Ok(row) => row.into_owned(),
+            Err(e) => {
+                self.status = format!("row {}: {:#}", selected, e);
+                return Ok(ConsoleState::EditTable(table_name));
+            }
+        };
 
         let mut text_areas: Vec<TextArea> = default_row_data
             .iter()
@@ -306,81 +2071,121 @@ impl App {
         for t in &mut text_areas {
             inactivate(t);
         }
-        activate(&mut text_areas[0]);
+        activate(&mut text_areas[which]);
 
-        loop {
-            terminal.draw(|f| {
-                // グローバルの画面領域分割
-                let global_chunks = Layout::default()
-                    .direction(Direction::Vertical)
-                    .constraints([Constraint::Percentage(10), Constraint::Percentage(90)])
-                    .margin(2)
-                    .split(f.size());
+        let draw = |f: &mut Frame<B>, text_areas: &[TextArea], which: usize| {
+            // グローバルの画面領域分割
+            let global_chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Percentage(10), Constraint::Percentage(90)])
+                .margin(2)
+                .split(f.size());
+
+            // ヘルプ情報
+            let help_info = Paragraph::new(format!(
+                "Tab/Shift+Tab: 項目の移動 ({}/{})  Enter: 値の一覧から選ぶ（Enumの列）  Ctrl+S: 保存  Ctrl+L: NULLにする  Esc: 戻る",
+                which + 1,
+                header_len
+            ))
+            .block(Block::default().borders(Borders::ALL))
+            .alignment(tui::layout::Alignment::Center);
+
+            // エディタ
+            let editor_chunks = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(10), Constraint::Percentage(90)].as_ref())
+                .margin(2)
+                .split(global_chunks[1]);
 
-                // ヘルプ情報
-                let help_info = Paragraph::new("help information here.")
+            // エディタのヘッダ部分
+            let header = col_names.iter().map(|name| {
+                Paragraph::new(name.as_str())
                     .block(Block::default().borders(Borders::ALL))
-                    .alignment(tui::layout::Alignment::Center);
+                    .alignment(tui::layout::Alignment::Center)
+            });
+            // 編集中の項目を含むページの項目だけを、ヘッダと編集部分で同じ高さに並べる
+            let first = which / FIELDS_PER_PAGE * FIELDS_PER_PAGE;
+            let constraints =
+                [Constraint::Percentage(80 / FIELDS_PER_PAGE as u16); FIELDS_PER_PAGE];
+            let header_chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(constraints)
+                .split(editor_chunks[0]);
 
-                // エディタ
-                let editor_chunks = Layout::default()
-                    .direction(Direction::Horizontal)
-                    .constraints([Constraint::Percentage(10), Constraint::Percentage(90)].as_ref())
-                    .margin(2)
-                    .split(global_chunks[1]);
+            // エディタの編集部分
+            let val_editing_chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(constraints)
+                .split(editor_chunks[1]);
 
-                // エディタのヘッダ部分
-                let header = col_names.clone().into_iter().map(|name| {
-                    Paragraph::new(name)
-                        .block(Block::default().borders(Borders::ALL))
-                        .alignment(tui::layout::Alignment::Center)
-                });
-                let constraints = vec![Constraint::Percentage(80 / header_len as u16); header_len];
-                let header_chunks = Layout::default()
-                    .direction(Direction::Vertical)
-                    .constraints(constraints.as_ref())
-                    .split(editor_chunks[0]);
+            // ヘルプ表示
+            f.render_widget(help_info, global_chunks[0]);
+            // ヘッダ　カラム名\n[型]の表示
+            for (paragraph, chunk) in header.skip(first).zip(header_chunks) {
+                f.render_widget(paragraph, chunk);
+            }
+            // 編集エリアの表示
+            for (textarea, chunk) in text_areas.iter().skip(first).zip(val_editing_chunks) {
+                let widget = textarea.widget();
+                f.render_widget(widget, chunk);
+            }
+        };
+        let edited_row = |text_areas: &[TextArea]| -> Vec<String> {
+            text_areas.iter().map(|t| t.lines().join("\n")).collect()
+        };
 
-                // エディタの編集部分
-                let val_editing_chunks = Layout::default()
-                    .direction(Direction::Vertical)
-                    .constraints([Constraint::Percentage(80 / 5); 5])
-                    .split(editor_chunks[1]);
-
-                // ヘルプ表示
-                f.render_widget(help_info, global_chunks[0]);
-                // ヘッダ　カラム名\n[型]の表示
-                for (paragraph, chunk) in header.zip(header_chunks) {
-                    f.render_widget(paragraph, chunk);
-                }
-                // 編集エリアの表示
-                for (textarea, chunk) in text_areas.iter().zip(val_editing_chunks) {
-                    let widget = textarea.widget();
-                    f.render_widget(widget, chunk);
-                }
-            })?;
+        loop {
+            terminal.draw(|f| draw(f, &text_areas, which))?;
 
             // キー入力判定
-            if let Event::Key(key_event) = event::read()? {
-                match key_event {
-                    // テーブル編集に戻る
+            self.autosave_if_due()?;
+            if let Some(Event::Key(key_event)) = poll_event()? {
+                // 編集中の項目がEnumの列なら、その値の一覧
+                let variants = columns.get(which).and_then(|c| match &c.data_type {
+                    DataType::Enum(variants) => Some(variants.clone()),
+                    _ => None,
+                });
+                // 宣言されたEnumの列だけ一覧にない値を入力させない。推論したEnumは推論に使った行の値の一覧なので自由に入力できる
+                let restricted = variants.is_some() && columns[which].declared;
+                let save = match key_event {
+                    // テーブル編集に戻る。未保存の変更があれば確認する
                     KeyEvent {
                         code: KeyCode::Esc, ..
-                    } => return Ok(ConsoleState::EditTable(table_name)),
+                    } => {
+                        if edited_row(&text_areas) == default_row_data {
+                            return Ok(ConsoleState::EditTable(table_name));
+                        }
+                        let choice = ask(
+                            terminal,
+                            |f| draw(f, &text_areas, which),
+                            "行に未保存の変更があります",
+                            &[],
+                            &[
+                                (KeyCode::Char('s'), "保存して戻る"),
+                                (KeyCode::Char('d'), "破棄して戻る"),
+                                (KeyCode::Esc, "編集を続ける"),
+                            ],
+                        )?;
+                        match choice {
+                            KeyCode::Char('s') => true,
+                            KeyCode::Char('d') => return Ok(ConsoleState::EditTable(table_name)),
+                            _ => false,
+                        }
+                    }
                     // 保存
                     KeyEvent {
                         code: KeyCode::Char('s'),
                         modifiers: KeyModifiers::CONTROL,
                         ..
+                    } => true,
+                    // 編集中のセルをNULLにする
+                    KeyEvent {
+                        code: KeyCode::Char('l'),
+                        modifiers: KeyModifiers::CONTROL,
+                        ..
                     } => {
-                        for (idx, cell_value) in data_table.values
-                            [data_table.state.selected().unwrap()]
-                        .iter_mut()
-                        .enumerate()
-                        {
-                            *cell_value = text_areas[idx].lines().join("\n");
-                        }
-                        return Ok(ConsoleState::EditTable(table_name));
+                        replace(&mut text_areas[which], nulls.token());
+                        false
                     }
                     // 編集セルの移動　逆
                     KeyEvent {
@@ -396,6 +2201,7 @@ impl App {
                         }
 
                         activate(&mut text_areas[which]);
+                        false
                     }
                     // 編集セルの移動　正
                     KeyEvent {
@@ -406,11 +2212,223 @@ impl App {
                         inactivate(&mut text_areas[which]);
                         which = (which + 1) % header_len;
                         activate(&mut text_areas[which]);
+                        false
+                    }
+                    // Enumの列は値の一覧から選ばせる。推論したEnumの列ではスペースは入力に使う
+                    KeyEvent {
+                        code: code @ (KeyCode::Enter | KeyCode::Char(' ')),
+                        modifiers: KeyModifiers::NONE,
+                        ..
+                    } if variants.is_some() && (restricted || code == KeyCode::Enter) => {
+                        let column = &columns[which];
+                        let mut choices = variants.unwrap_or_default();
+                        if column.nullable {
+                            choices.push(nulls.token().to_string());
+                        }
+                        let labels: Vec<String> = choices
+                            .iter()
+                            .map(|c| match nulls.is_null(c) {
+                                true => "NULL".to_string(),
+                                false => c.clone(),
+                            })
+                            .collect();
+                        let current = text_areas[which].lines().join("\n");
+                        let selected = choices.iter().position(|c| *c == current);
+                        let picked = pick(
+                            terminal,
+                            |f| draw(f, &text_areas, which),
+                            &column.name,
+                            &labels,
+                            selected,
+                        )?;
+                        if let Some(i) = picked {
+                            replace(&mut text_areas[which], &choices[i]);
+                        }
+                        false
                     }
+                    KeyEvent {
+                        modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+                        ..
+                    } if restricted => false,
                     // その他の入力は編集エリアに反映
                     key_event => {
                         let input = Input::from(key_event);
                         text_areas[which].input(input);
+                        false
+                    }
+                };
+                if save {
+                    let row = edited_row(&text_areas);
+                    // 変更したセルに列に合わない値があれば確認する
+                    let invalid: Vec<(usize, String)> = (0..row.len())
+                        .filter(|i| default_row_data.get(*i) != row.get(*i))
+                        .filter_map(|i| Some((i, columns.get(i)?.check(&row[i], &nulls)?)))
+                        .collect();
+                    if !invalid.is_empty() {
+                        let lines: Vec<String> = invalid
+                            .iter()
+                            .map(|(i, reason)| format!("{}: {}", columns[*i].name, reason))
+                            .collect();
+                        let choice = ask(
+                            terminal,
+                            |f| draw(f, &text_areas, which),
+                            "列の型に合わない値があります",
+                            &lines,
+                            &[
+                                (KeyCode::Char('s'), "そのまま保存"),
+                                (KeyCode::Esc, "編集に戻る"),
+                            ],
+                        )?;
+                        if choice != KeyCode::Char('s') {
+                            // 最初の合わない値のセルに移る
+                            inactivate(&mut text_areas[which]);
+                            which = invalid[0].0;
+                            activate(&mut text_areas[which]);
+                            continue;
+                        }
+                    }
+                    if row != default_row_data {
+                        let data_table = self.get_table_mut(table_name.clone()).unwrap();
+                        match data_table.rows.get_mut(selected) {
+                            Ok(r) => {
+                                *r = row;
+                                data_table.touch();
+                            }
+                            Err(e) => self.status = format!("row {}: {:#}", selected, e),
+                        }
+                    }
+                    return Ok(ConsoleState::EditTable(table_name));
+                }
+            }
+        }
+    }
+
+    fn header_editing<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        table_name: String,
+    ) -> Result<ConsoleState> {
+        fn activate(textarea: &mut TextArea<'_>, active: bool) {
+            let (style, title) = match active {
+                true => (Style::default(), "アクティブ"),
+                false => (Style::default().fg(Color::DarkGray), "非アクティブ"),
+            };
+            textarea.set_cursor_style(match active {
+                true => Style::default().add_modifier(Modifier::REVERSED),
+                false => Style::default(),
+            });
+            textarea.set_block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .style(style)
+                    .title(title),
+            );
+        }
+
+        let data_table = self.get_table(table_name.clone()).unwrap();
+        let mut text_areas: Vec<TextArea> = data_table
+            .schema
+            .columns
+            .iter()
+            .map(|c| TextArea::from([c.name.clone()]))
+            .collect();
+        // 列のないテーブルは1列目の入力欄から始める
+        if text_areas.is_empty() {
+            text_areas.push(TextArea::default());
+        }
+        let mut which: usize = 0;
+        for (idx, t) in text_areas.iter_mut().enumerate() {
+            activate(t, idx == which);
+        }
+
+        loop {
+            terminal.draw(|f| {
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(3), Constraint::Min(0)])
+                    .margin(2)
+                    .split(f.size());
+                let help_info = Paragraph::new(
+                    "Tab/Shift+Tab: 移動  Ctrl+N: 列を追加  Ctrl+S: 確定  Esc: 取消",
+                )
+                .block(Block::default().borders(Borders::ALL).title("ヘッダ編集"))
+                .alignment(Alignment::Center);
+                f.render_widget(help_info, chunks[0]);
+
+                let constraints = vec![Constraint::Length(3); text_areas.len()];
+                let column_chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints(constraints)
+                    .split(chunks[1]);
+                for (textarea, chunk) in text_areas.iter().zip(column_chunks) {
+                    f.render_widget(textarea.widget(), chunk);
+                }
+            })?;
+
+            self.autosave_if_due()?;
+            if let Some(Event::Key(key_event)) = poll_event()? {
+                match key_event {
+                    KeyEvent {
+                        code: KeyCode::Esc, ..
+                    } => return Ok(ConsoleState::EditTable(table_name)),
+                    // 確定。既存の列は名前を変え、増えた分は列を追加する
+                    KeyEvent {
+                        code: KeyCode::Char('s'),
+                        modifiers: KeyModifiers::CONTROL,
+                        ..
+                    } => {
+                        // 列の追加は add_column で記録されるので、名前を変えたときだけ記録する
+                        let data_table = self.get_table_mut(table_name.clone()).unwrap();
+                        let mut renamed = false;
+                        for (idx, textarea) in text_areas.iter().enumerate() {
+                            let name = textarea.lines().join("");
+                            match data_table.schema.columns.get_mut(idx) {
+                                Some(column) if column.name == name => {}
+                                Some(column) => {
+                                    column.name = name;
+                                    renamed = true;
+                                }
+                                None => data_table.add_column(name),
+                            }
+                        }
+                        if renamed {
+                            data_table.touch();
+                        }
+                        return Ok(ConsoleState::EditTable(table_name));
+                    }
+                    // 列を追加
+                    KeyEvent {
+                        code: KeyCode::Char('n'),
+                        modifiers: KeyModifiers::CONTROL,
+                        ..
+                    } => {
+                        activate(&mut text_areas[which], false);
+                        text_areas.push(TextArea::default());
+                        which = text_areas.len() - 1;
+                        activate(&mut text_areas[which], true);
+                    }
+                    KeyEvent {
+                        code: KeyCode::BackTab,
+                        ..
+                    } => {
+                        activate(&mut text_areas[which], false);
+                        which = (which + text_areas.len() - 1) % text_areas.len();
+                        activate(&mut text_areas[which], true);
+                    }
+                    KeyEvent {
+                        code: KeyCode::Tab, ..
+                    } => {
+                        activate(&mut text_areas[which], false);
+                        which = (which + 1) % text_areas.len();
+                        activate(&mut text_areas[which], true);
+                    }
+                    // 列名は1行なので改行は入力しない
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        ..
+                    } => {}
+                    key_event => {
+                        text_areas[which].input(Input::from(key_event));
                     }
                 }
             }
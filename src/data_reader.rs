@@ -1,18 +1,152 @@
 extern crate csv;
+use crate::dialect::{Dialect, SNIFF_BYTES};
+use crate::encoding::TextEncoding;
+use crate::error::LoadError;
 use crate::prelude::*;
+use crate::row_store::RowStore;
 use csv::{Error, StringRecord};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 
-pub fn get_string_records(path: &Path) -> Result<Vec<StringRecord>> {
-    let text = get_text(path);
-    let mut reader = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .flexible(true)
-        .from_reader(text.as_bytes());
+/// これ以上のサイズのファイルは全体を読み込まず、行の索引を作って遅延パースする
+pub const INDEXED_LOAD_BYTES: u64 = 16 * 1024 * 1024;
 
-    let mut res: Vec<StringRecord> = Vec::new();
+/// 読み込んだCSVファイルの内容
+pub struct CsvSource {
+    pub header: Vec<String>,
+    pub rows: RowStore,
+    pub dialect: Dialect,
+    pub encoding: TextEncoding,
+}
+
+/// ファイルを読み込み、推定した方言・文字コードとともにレコードを返す
+pub fn get_string_records(path: &Path) -> Result<CsvSource, LoadError> {
+    let size = std::fs::metadata(path)?.len();
+    if size >= INDEXED_LOAD_BYTES {
+        if let Some(source) = index_records(path, size)? {
+            return Ok(source);
+        }
+    }
+
+    let bytes = get_bytes(path)?;
+    let encoding = TextEncoding::detect(&bytes);
+    let text = encoding.decode(&bytes)?;
+    let mut dialect = Dialect::sniff(text.as_bytes());
+    dialect.final_newline = text.is_empty() || text.ends_with('\n');
+    let mut reader = dialect.reader_builder().from_reader(text.as_bytes());
+
+    let mut records: Vec<StringRecord> = Vec::new();
     for record in reader.records() {
         let record = record?;
-        res.push(record);
+        records.push(record);
+    }
+
+    // 列数の足りない行は空文字で埋める
+    let max_len = records.iter().map(|e| e.len()).max().unwrap_or(0);
+    let mut data = records
+        .iter()
+        .map(|record| {
+            let mut row: Vec<String> = record.iter().map(String::from).collect();
+            row.resize(max_len, String::new());
+            row
+        })
+        .collect::<Vec<Vec<String>>>()
+        .into_iter();
+    let header = data.next().unwrap_or_default();
+
+    Ok(CsvSource {
+        header,
+        rows: RowStore::from_rows(data.collect()),
+        dialect,
+        encoding,
+    })
+}
+
+/// 先頭部分から文字コードと方言を推定し、行の索引を作る
+/// バイト単位で行を切り出せない文字コードの場合や、先頭部分より後にUTF-8として読めない行がある場合はNone
+fn index_records(path: &Path, size: u64) -> Result<Option<CsvSource>, LoadError> {
+    let mut file = File::open(path)?;
+    let mut sample = Vec::with_capacity(SNIFF_BYTES);
+    (&mut file)
+        .take(SNIFF_BYTES as u64)
+        .read_to_end(&mut sample)?;
+
+    // サンプル末尾でマルチバイト文字が切れていても判定できるよう、UTF-8として読める部分だけ使う
+    let valid_len = match std::str::from_utf8(&sample) {
+        Ok(_) => sample.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => return Ok(None),
+    };
+    let encoding = TextEncoding::detect(&sample[..valid_len]);
+    if !matches!(encoding, TextEncoding::Utf8 | TextEncoding::Utf8Bom) {
+        return Ok(None);
+    }
+
+    let mut dialect = Dialect::sniff(&sample[..valid_len]);
+    let mut last = [0u8; 1];
+    file.seek(SeekFrom::Start(size - 1))?;
+    file.read_exact(&mut last)?;
+    dialect.final_newline = last[0] == b'\n';
+
+    let Some((header, rows)) = RowStore::index(path, dialect)? else {
+        return Ok(None);
+    };
+    Ok(Some(CsvSource {
+        header,
+        rows,
+        dialect,
+        encoding,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::Terminator;
+    use encoding_rs::SHIFT_JIS;
+
+    /// 書き込んでから読み込み、同じ方言・文字コードで書き戻したバイト列を返す
+    fn round_trip(name: &str, bytes: &[u8]) -> (CsvSource, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("crate-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let source = get_string_records(&path);
+        std::fs::remove_file(&path).unwrap();
+        let source = source.unwrap();
+        let rows: Vec<Result<Vec<String>>> = source
+            .rows
+            .iter()
+            .map(|row| Ok(row.unwrap().into_owned()))
+            .collect();
+        let text = write_string_records(&source.header, rows, &source.dialect).unwrap();
+        let written = source.encoding.encode(&text).unwrap();
+        (source, written)
+    }
+
+    #[test]
+    fn keeps_dialect_encoding_and_line_endings() {
+        let (bytes, _, _) = SHIFT_JIS.encode("名前;値\r\n\"a;b\";1\r\nテスト;2");
+        let (source, written) = round_trip("sjis.csv", &bytes);
+        assert_eq!(source.encoding, TextEncoding::ShiftJis);
+        assert_eq!(source.dialect.delimiter, b';');
+        assert_eq!(source.dialect.terminator, Terminator::CrLf);
+        assert!(!source.dialect.final_newline);
+        assert_eq!(source.header, ["名前", "値"]);
+        assert_eq!(written, bytes.as_ref());
+    }
+
+    #[test]
+    fn keeps_bom_and_tabs() {
+        let bytes = "\u{FEFF}id\tname\n1\tx\n".as_bytes();
+        let (source, written) = round_trip("bom.tsv", bytes);
+        assert_eq!(source.encoding, TextEncoding::Utf8Bom);
+        assert_eq!(source.dialect.delimiter, b'\t');
+        assert_eq!(source.header, ["id", "name"]);
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn pads_short_rows() {
+        let (source, _) = round_trip("short.csv", b"a,b,c\n1\n");
+        assert_eq!(source.rows.get(0).unwrap().as_ref(), ["1", "", ""]);
     }
-    Ok(res)
 }
@@ -0,0 +1,114 @@
+extern crate csv;
+use crate::dialect::Dialect;
+use crate::prelude::*;
+
+/// ヘッダ行とレコードを指定の方言でCSV文字列に変換する
+/// 区切り文字・改行・クォートを含むセルのみクォートする
+pub fn write_string_records<H, I, R, S>(
+    header: &[H],
+    records: I,
+    dialect: &Dialect,
+) -> Result<String>
+where
+    H: AsRef<[u8]>,
+    I: IntoIterator<Item = Result<R>>,
+    R: AsRef<[S]>,
+    S: AsRef<[u8]>,
+{
+    let mut writer = dialect.writer_builder().from_writer(Vec::new());
+
+    // カラムが1つもない場合はヘッダ行を書かない
+    if !header.is_empty() {
+        writer.write_record(header)?;
+    }
+    for record in records {
+        writer.write_record(record?.as_ref())?;
+    }
+
+    let mut text = String::from_utf8(writer.into_inner()?)?;
+    // 元ファイルが改行で終わっていなければ末尾の改行を落とす
+    if !dialect.final_newline && text.ends_with(dialect.terminator.as_str()) {
+        text.truncate(text.len() - dialect.terminator.as_str().len());
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::{Escape, Terminator};
+
+    fn write(header: &[&str], rows: &[&[&str]], dialect: &Dialect) -> String {
+        let records = rows.iter().map(|row| Ok(row.to_vec()));
+        write_string_records(header, records, dialect).unwrap()
+    }
+
+    fn read(text: &str, dialect: &Dialect) -> Vec<Vec<String>> {
+        dialect
+            .reader_builder()
+            .from_reader(text.as_bytes())
+            .records()
+            .map(|r| r.unwrap().iter().map(String::from).collect())
+            .collect()
+    }
+
+    #[test]
+    fn quotes_only_cells_that_need_it() {
+        let text = write(
+            &["id", "name"],
+            &[
+                &["1", "a,b"],
+                &["2", "say \"hi\""],
+                &["3", "line\nbreak"],
+                &["4", "plain"],
+            ],
+            &Dialect::default(),
+        );
+        assert_eq!(
+            text,
+            "id,name\n1,\"a,b\"\n2,\"say \"\"hi\"\"\"\n3,\"line\nbreak\"\n4,plain\n"
+        );
+    }
+
+    #[test]
+    fn round_trips_through_the_reader() {
+        let rows: &[&[&str]] = &[
+            &["id", "memo"],
+            &["1", "x;y"],
+            &["2", "it's \"q\""],
+            &["3", ""],
+        ];
+        for dialect in [
+            Dialect::default(),
+            Dialect {
+                delimiter: b';',
+                quote: b'\'',
+                escape: Escape::Backslash,
+                terminator: Terminator::CrLf,
+                final_newline: false,
+            },
+        ] {
+            let text = write(rows[0], &rows[1..], &dialect);
+            let expected: Vec<Vec<String>> = rows
+                .iter()
+                .map(|row| row.iter().map(|v| v.to_string()).collect())
+                .collect();
+            assert_eq!(read(&text, &dialect), expected, "{:?}", dialect);
+        }
+    }
+
+    #[test]
+    fn keeps_line_endings_and_missing_final_newline() {
+        let dialect = Dialect {
+            terminator: Terminator::CrLf,
+            final_newline: false,
+            ..Dialect::default()
+        };
+        assert_eq!(write(&["a", "b"], &[&["1", "2"]], &dialect), "a,b\r\n1,2");
+    }
+
+    #[test]
+    fn omits_header_when_there_are_no_columns() {
+        assert_eq!(write(&[], &[], &Dialect::default()), "");
+    }
+}
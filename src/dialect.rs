@@ -0,0 +1,235 @@
+use crate::prelude::*;
+use std::fmt::Display;
+
+/// 方言の推定に使う先頭バイト数
+pub const SNIFF_BYTES: usize = 8 * 1024;
+
+/// 区切り文字の候補（優先順）
+const DELIMITER_CANDIDATES: [u8; 4] = [b',', b'\t', b';', b'|'];
+/// クォート文字の候補（優先順）
+const QUOTE_CANDIDATES: [u8; 2] = [b'"', b'\''];
+
+/// クォート文字のエスケープ方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    /// `""` のようにクォートを重ねる（RFC 4180）
+    Doubled,
+    /// `\"` のようにバックスラッシュを前置する
+    Backslash,
+}
+
+/// 行末の改行コード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    Lf,
+    CrLf,
+}
+impl Display for Terminator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Terminator::Lf => write!(f, "LF"),
+            Terminator::CrLf => write!(f, "CRLF"),
+        }
+    }
+}
+impl Terminator {
+    /// クォート外で最初に現れる改行から改行コードを判定する
+    /// 改行がなければNone
+    pub fn detect(text: &[u8], quote: u8) -> Option<Terminator> {
+        let mut in_quotes = false;
+        for (i, b) in text.iter().enumerate() {
+            if *b == quote {
+                in_quotes = !in_quotes;
+            } else if *b == b'\n' && !in_quotes {
+                return match i > 0 && text[i - 1] == b'\r' {
+                    true => Some(Terminator::CrLf),
+                    false => Some(Terminator::Lf),
+                };
+            }
+        }
+        None
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Terminator::Lf => "\n",
+            Terminator::CrLf => "\r\n",
+        }
+    }
+}
+
+/// CSVファイルの方言
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dialect {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub escape: Escape,
+    pub terminator: Terminator,
+    /// ファイル末尾が改行で終わっているか
+    pub final_newline: bool,
+}
+impl Default for Dialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            escape: Escape::Doubled,
+            terminator: Terminator::Lf,
+            final_newline: true,
+        }
+    }
+}
+impl Display for Dialect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.delimiter {
+            b',' => write!(f, "CSV"),
+            b'\t' => write!(f, "TSV"),
+            d => write!(f, "'{}'区切り", d as char),
+        }
+    }
+}
+impl Dialect {
+    /// ファイル先頭のサンプルから方言を推定する
+    pub fn sniff(sample: &[u8]) -> Dialect {
+        let sample = &sample[..std::cmp::min(sample.len(), SNIFF_BYTES)];
+        // サンプルの途中で切れた最終行は判定に使わない
+        let sample = match sample.iter().rposition(|b| *b == b'\n') {
+            Some(pos) if sample.len() == SNIFF_BYTES => &sample[..=pos],
+            _ => sample,
+        };
+
+        let quote = Self::sniff_quote(sample);
+        let escape = Self::sniff_escape(sample, quote);
+        let terminator = Terminator::detect(sample, quote).unwrap_or(Terminator::Lf);
+
+        // 各候補で読み込み、列数が最も揃う区切り文字を採用する
+        let mut best = (0, b',');
+        for delimiter in DELIMITER_CANDIDATES {
+            let candidate = Dialect {
+                delimiter,
+                quote,
+                escape,
+                terminator,
+                final_newline: true,
+            };
+            let mut reader = candidate.reader_builder().from_reader(sample);
+            let mut counts = BTreeMap::<usize, usize>::new();
+            for record in reader.records().flatten() {
+                *counts.entry(record.len()).or_insert(0) += 1;
+            }
+            // 最頻の列数とその出現回数。列数1は区切られていないとみなす
+            let score = counts
+                .iter()
+                .filter(|(len, _)| **len > 1)
+                .map(|(_, freq)| *freq)
+                .max()
+                .unwrap_or(0);
+            if score > best.0 {
+                best = (score, delimiter);
+            }
+        }
+
+        Dialect {
+            delimiter: best.1,
+            quote,
+            escape,
+            terminator,
+            final_newline: true,
+        }
+    }
+    fn sniff_quote(sample: &[u8]) -> u8 {
+        // フィールド先頭に現れた回数が最も多いクォート文字を採用する
+        let at_field_start = |q: u8| {
+            sample
+                .windows(2)
+                .filter(|w| w[1] == q && (DELIMITER_CANDIDATES.contains(&w[0]) || w[0] == b'\n'))
+                .count()
+                + usize::from(sample.first() == Some(&q))
+        };
+        let mut best = (0, b'"');
+        for q in QUOTE_CANDIDATES {
+            let count = at_field_start(q);
+            if count > best.0 {
+                best = (count, q);
+            }
+        }
+        best.1
+    }
+    fn sniff_escape(sample: &[u8], quote: u8) -> Escape {
+        let doubled = sample.windows(2).filter(|w| w == &[quote, quote]).count();
+        let backslash = sample.windows(2).filter(|w| w == &[b'\\', quote]).count();
+        if backslash > 0 && doubled == 0 {
+            Escape::Backslash
+        } else {
+            Escape::Doubled
+        }
+    }
+    pub fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(self.delimiter)
+            .quote(self.quote);
+        if self.escape == Escape::Backslash {
+            builder.escape(Some(b'\\')).double_quote(false);
+        }
+        builder
+    }
+    pub fn writer_builder(&self) -> csv::WriterBuilder {
+        let mut builder = csv::WriterBuilder::new();
+        builder
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(self.delimiter)
+            .quote(self.quote);
+        if self.escape == Escape::Backslash {
+            builder.escape(b'\\').double_quote(false);
+        }
+        match self.terminator {
+            Terminator::Lf => builder.terminator(csv::Terminator::Any(b'\n')),
+            Terminator::CrLf => builder.terminator(csv::Terminator::CRLF),
+        };
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_delimiter() {
+        assert_eq!(Dialect::sniff(b"a,b,c\n1,2,3\n").delimiter, b',');
+        assert_eq!(Dialect::sniff(b"a\tb\tc\n1\t2\t3\n").delimiter, b'\t');
+        assert_eq!(Dialect::sniff(b"a;b;c\n1,5;2;3\n").delimiter, b';');
+        assert_eq!(Dialect::sniff(b"a|b\n1|2\n").delimiter, b'|');
+    }
+
+    #[test]
+    fn sniffs_quote_and_escape() {
+        let dialect = Dialect::sniff(b"'a','b'\n'it\\'s','x'\n");
+        assert_eq!(dialect.quote, b'\'');
+        assert_eq!(dialect.escape, Escape::Backslash);
+
+        let dialect = Dialect::sniff(b"\"a\",\"b\"\n\"say \"\"hi\"\"\",x\n");
+        assert_eq!(dialect.quote, b'"');
+        assert_eq!(dialect.escape, Escape::Doubled);
+    }
+
+    #[test]
+    fn detects_terminator_outside_quotes() {
+        assert_eq!(
+            Terminator::detect(b"a,b\r\n1,2\r\n", b'"'),
+            Some(Terminator::CrLf)
+        );
+        assert_eq!(
+            Terminator::detect(b"a,b\n1,2\n", b'"'),
+            Some(Terminator::Lf)
+        );
+        assert_eq!(
+            Terminator::detect(b"\"a\nb\",c\r\n", b'"'),
+            Some(Terminator::CrLf)
+        );
+        assert_eq!(Terminator::detect(b"a,b", b'"'), None);
+    }
+}
@@ -0,0 +1,460 @@
+use crate::prelude::*;
+
+/// LCSで行を対応付ける上限（差分区間の行数の積）。超えたら行順で対応付ける
+const LCS_LIMIT: usize = 4_000_000;
+
+/// 行単位の変更
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowChange {
+    /// 新しい版で追加された行（新しい版のindex）
+    Added(usize),
+    /// 古い版から削除された行（古い版のindex）
+    Removed(usize),
+    /// 対応する行の中で値が変わったセル
+    Changed {
+        old: usize,
+        new: usize,
+        cells: Vec<usize>,
+    },
+}
+
+/// 2つの版の行の対応付け
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Same(usize, usize),
+    Changed(usize, usize),
+    Removed(usize),
+    Added(usize),
+}
+
+/// 比較する版の内容
+#[derive(Debug, Clone, Default)]
+pub struct Version {
+    /// 画面に表示する名前（"ディスク"、スナップショット名など）
+    pub label: String,
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// 2つの版の差分
+#[derive(Debug, Clone, Default)]
+pub struct TableDiff {
+    pub header_changed: bool,
+    pub changes: Vec<RowChange>,
+    /// 変わらなかった行も含む、行の対応付け
+    pub alignments: Vec<Alignment>,
+    /// キー列で対応付けたか
+    pub keyed: bool,
+}
+impl TableDiff {
+    pub fn is_empty(&self) -> bool {
+        !self.header_changed && self.changes.is_empty()
+    }
+    /// 追加・削除・変更された行数
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for change in self.changes.iter() {
+            match change {
+                RowChange::Added(_) => counts.0 += 1,
+                RowChange::Removed(_) => counts.1 += 1,
+                RowChange::Changed { .. } => counts.2 += 1,
+            }
+        }
+        counts
+    }
+}
+
+/// 2つの版のヘッダと行を比較する
+pub fn diff_tables(
+    old_header: &[String],
+    old_rows: &[Vec<String>],
+    new_header: &[String],
+    new_rows: &[Vec<String>],
+) -> TableDiff {
+    diff_tables_by(old_header, old_rows, new_header, new_rows, None)
+}
+
+/// 2つの版のヘッダと行を比較する
+/// キー列が指定され、両方の版にその列があればキーの値で行を対応付け、なければ行の並びで対応付ける
+pub fn diff_tables_by(
+    old_header: &[String],
+    old_rows: &[Vec<String>],
+    new_header: &[String],
+    new_rows: &[Vec<String>],
+    key: Option<&str>,
+) -> TableDiff {
+    let key_columns = key.and_then(|key| {
+        let old = old_header.iter().position(|c| c == key)?;
+        let new = new_header.iter().position(|c| c == key)?;
+        Some((old, new))
+    });
+    let alignments = match key_columns {
+        Some(columns) => align_rows_by_key(old_rows, new_rows, columns),
+        None => align_rows(old_rows, new_rows),
+    };
+    let changes = alignments
+        .iter()
+        .filter_map(|a| match *a {
+            Alignment::Same(..) => None,
+            Alignment::Changed(old, new) => Some(RowChange::Changed {
+                old,
+                new,
+                cells: changed_cells(&old_rows[old], &new_rows[new]),
+            }),
+            Alignment::Removed(old) => Some(RowChange::Removed(old)),
+            Alignment::Added(new) => Some(RowChange::Added(new)),
+        })
+        .collect();
+    TableDiff {
+        header_changed: old_header != new_header,
+        changes,
+        alignments,
+        keyed: key_columns.is_some(),
+    }
+}
+
+/// キー列の値が同じ行を対応付ける。同じキーが複数あれば出現順に対応付ける
+/// 新しい版の並び順に並べ、削除された行は古い版で直前にあった行の後ろに置く
+fn align_rows_by_key(
+    old_rows: &[Vec<String>],
+    new_rows: &[Vec<String>],
+    (old_key, new_key): (usize, usize),
+) -> Vec<Alignment> {
+    let mut by_key = BTreeMap::<&str, std::collections::VecDeque<usize>>::new();
+    for (i, row) in old_rows.iter().enumerate() {
+        let key = row.get(old_key).map_or("", String::as_str);
+        by_key.entry(key).or_default().push_back(i);
+    }
+    let mut matched = vec![false; old_rows.len()];
+    let pairs: Vec<Option<usize>> = new_rows
+        .iter()
+        .map(|row| {
+            let key = row.get(new_key).map_or("", String::as_str);
+            let i = by_key
+                .get_mut(key)
+                .and_then(|indices| indices.pop_front())?;
+            matched[i] = true;
+            Some(i)
+        })
+        .collect();
+
+    let mut alignments = Vec::new();
+    let mut next_old = 0;
+    let mut flush_removed = |until: usize, alignments: &mut Vec<Alignment>| {
+        while next_old < until {
+            if !matched[next_old] {
+                alignments.push(Alignment::Removed(next_old));
+            }
+            next_old += 1;
+        }
+    };
+    for (j, pair) in pairs.into_iter().enumerate() {
+        match pair {
+            Some(i) => {
+                flush_removed(i, &mut alignments);
+                alignments.push(match old_rows[i] == new_rows[j] {
+                    true => Alignment::Same(i, j),
+                    false => Alignment::Changed(i, j),
+                });
+            }
+            None => alignments.push(Alignment::Added(j)),
+        }
+    }
+    flush_removed(old_rows.len(), &mut alignments);
+    alignments
+}
+
+/// 行を先頭から順に対応付ける
+/// 共通の先頭・末尾を除いた区間をLCSで対応付け、対応しなかった行のうち
+/// 隣り合う削除と追加は「変更」としてまとめる
+pub fn align_rows(old_rows: &[Vec<String>], new_rows: &[Vec<String>]) -> Vec<Alignment> {
+    let prefix = old_rows
+        .iter()
+        .zip(new_rows.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_rows[prefix..]
+        .iter()
+        .rev()
+        .zip(new_rows[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = prefix..old_rows.len() - suffix;
+    let new_mid = prefix..new_rows.len() - suffix;
+
+    let pairs = match old_mid.len() * new_mid.len() <= LCS_LIMIT {
+        true => lcs_pairs(&old_rows[old_mid.clone()], &new_rows[new_mid.clone()]),
+        false => Vec::new(),
+    };
+
+    let mut alignments: Vec<Alignment> = (0..prefix).map(|k| Alignment::Same(k, k)).collect();
+    let (mut i, mut j) = (0, 0);
+    let ends = (old_mid.len(), new_mid.len());
+    for (pi, pj) in pairs.into_iter().chain(std::iter::once(ends)) {
+        let removed: Vec<usize> = (i..pi).map(|k| k + prefix).collect();
+        let added: Vec<usize> = (j..pj).map(|k| k + prefix).collect();
+        let paired = std::cmp::min(removed.len(), added.len());
+        for k in 0..paired {
+            alignments.push(Alignment::Changed(removed[k], added[k]));
+        }
+        alignments.extend(removed[paired..].iter().map(|k| Alignment::Removed(*k)));
+        alignments.extend(added[paired..].iter().map(|k| Alignment::Added(*k)));
+        if pi < ends.0 {
+            alignments.push(Alignment::Same(pi + prefix, pj + prefix));
+        }
+        i = pi + 1;
+        j = pj + 1;
+    }
+    let old_end = old_rows.len() - suffix;
+    let new_end = new_rows.len() - suffix;
+    alignments.extend((0..suffix).map(|k| Alignment::Same(old_end + k, new_end + k)));
+    alignments
+}
+
+/// 値の異なるセルのindex
+pub fn changed_cells(old: &[String], new: &[String]) -> Vec<usize> {
+    let len = std::cmp::max(old.len(), new.len());
+    (0..len).filter(|i| old.get(*i) != new.get(*i)).collect()
+}
+
+/// 最長共通部分列で対応付いた行のindexの組
+fn lcs_pairs(old: &[Vec<String>], new: &[Vec<String>]) -> Vec<(usize, usize)> {
+    let (n, m) = (old.len(), new.len());
+    let mut table = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[at(i, j)] = match old[i] == new[j] {
+                true => table[at(i + 1, j + 1)] + 1,
+                false => std::cmp::max(table[at(i + 1, j)], table[at(i, j + 1)]),
+            };
+        }
+    }
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if table[at(i + 1, j)] >= table[at(i, j + 1)] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+/// 3方向マージの結果
+pub struct Merged {
+    pub rows: Vec<Vec<String>>,
+    /// 両方で異なる変更があった箇所の数。こちら側の変更を優先している
+    pub conflicts: usize,
+}
+
+/// 共通の元の版(base)に対する、こちら(ours)とあちら(theirs)の変更をまとめる
+pub fn merge3(base: &[Vec<String>], ours: &[Vec<String>], theirs: &[Vec<String>]) -> Merged {
+    /// 元の行ごとの変更と、元の行の後ろに追加された行
+    struct Side {
+        state: Vec<Alignment>,
+        inserted: BTreeMap<Option<usize>, Vec<usize>>,
+    }
+    fn side(base: &[Vec<String>], other: &[Vec<String>]) -> Side {
+        let mut state: Vec<Alignment> = (0..base.len()).map(Alignment::Removed).collect();
+        let mut inserted = BTreeMap::<Option<usize>, Vec<usize>>::new();
+        let mut anchor = None;
+        for a in align_rows(base, other) {
+            match a {
+                Alignment::Same(b, _) | Alignment::Changed(b, _) | Alignment::Removed(b) => {
+                    state[b] = a;
+                    anchor = Some(b);
+                }
+                Alignment::Added(j) => inserted.entry(anchor).or_default().push(j),
+            }
+        }
+        Side { state, inserted }
+    }
+
+    let o = side(base, ours);
+    let t = side(base, theirs);
+    let mut rows = Vec::new();
+    let mut conflicts = 0;
+
+    let emit_inserted = |anchor: Option<usize>, rows: &mut Vec<Vec<String>>| {
+        let theirs_rows = t
+            .inserted
+            .get(&anchor)
+            .into_iter()
+            .flatten()
+            .map(|j| &theirs[*j]);
+        let ours_rows = o
+            .inserted
+            .get(&anchor)
+            .into_iter()
+            .flatten()
+            .map(|j| &ours[*j]);
+        let mut added: Vec<&Vec<String>> = Vec::new();
+        for row in theirs_rows.chain(ours_rows) {
+            if !added.contains(&row) {
+                added.push(row);
+            }
+        }
+        rows.extend(added.into_iter().cloned());
+    };
+
+    emit_inserted(None, &mut rows);
+    for (b, base_row) in base.iter().enumerate() {
+        match (o.state[b], t.state[b]) {
+            (Alignment::Same(..), Alignment::Same(..)) => rows.push(base_row.clone()),
+            (Alignment::Same(..), Alignment::Changed(_, j)) => rows.push(theirs[j].clone()),
+            (Alignment::Changed(_, i), Alignment::Same(..)) => rows.push(ours[i].clone()),
+            (Alignment::Changed(_, i), Alignment::Changed(_, j)) => {
+                let (ours_row, theirs_row) = (&ours[i], &theirs[j]);
+                let len = std::cmp::max(ours_row.len(), theirs_row.len());
+                let cell = |row: &Vec<String>, c: usize| row.get(c).cloned().unwrap_or_default();
+                let merged = (0..len)
+                    .map(|c| {
+                        let (b, o, t) = (cell(base_row, c), cell(ours_row, c), cell(theirs_row, c));
+                        if o == b {
+                            t
+                        } else if t == b || t == o {
+                            o
+                        } else {
+                            conflicts += 1;
+                            o
+                        }
+                    })
+                    .collect();
+                rows.push(merged);
+            }
+            // 片方が消し、もう片方が変更した行は変更を残す
+            (Alignment::Removed(_), Alignment::Changed(_, j)) => {
+                conflicts += 1;
+                rows.push(theirs[j].clone());
+            }
+            (Alignment::Changed(_, i), Alignment::Removed(_)) => {
+                conflicts += 1;
+                rows.push(ours[i].clone());
+            }
+            _ => {}
+        }
+        emit_inserted(Some(b), &mut rows);
+    }
+
+    Merged { rows, conflicts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(data: &[&[&str]]) -> Vec<Vec<String>> {
+        data.iter()
+            .map(|row| row.iter().map(|v| v.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn finds_changed_and_added_rows() {
+        let header = rows(&[&["id", "v"]]).remove(0);
+        let old = rows(&[&["1", "a"], &["2", "b"], &["3", "c"]]);
+        let new = rows(&[&["1", "a"], &["2", "B"], &["4", "d"], &["3", "c"]]);
+        let diff = diff_tables(&header, &old, &header, &new);
+        assert!(!diff.header_changed);
+        assert_eq!(
+            diff.changes,
+            [
+                RowChange::Changed {
+                    old: 1,
+                    new: 1,
+                    cells: vec![1]
+                },
+                RowChange::Added(2)
+            ]
+        );
+        assert_eq!(diff.counts(), (1, 0, 1));
+    }
+
+    #[test]
+    fn reports_header_changes_only() {
+        let old_header = rows(&[&["id", "v"]]).remove(0);
+        let new_header = rows(&[&["id", "value"]]).remove(0);
+        let data = rows(&[&["1", "a"]]);
+        let diff = diff_tables(&old_header, &data, &new_header, &data);
+        assert!(diff.header_changed);
+        assert!(diff.changes.is_empty());
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn aligns_changed_rows_in_place() {
+        let old = rows(&[&["a"], &["b"], &["c"]]);
+        let new = rows(&[&["a"], &["x"], &["c"]]);
+        assert_eq!(
+            align_rows(&old, &new),
+            [
+                Alignment::Same(0, 0),
+                Alignment::Changed(1, 1),
+                Alignment::Same(2, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn aligns_inserted_and_removed_rows() {
+        let old = rows(&[&["a"], &["c"], &["d"]]);
+        let new = rows(&[&["a"], &["b"], &["c"]]);
+        assert_eq!(
+            align_rows(&old, &new),
+            [
+                Alignment::Same(0, 0),
+                Alignment::Added(1),
+                Alignment::Same(1, 2),
+                Alignment::Removed(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_changes_from_both_sides() {
+        let base = rows(&[&["1", "a", "x"], &["2", "b", "y"]]);
+        let ours = rows(&[&["1", "A", "x"], &["2", "b", "y"]]);
+        let theirs = rows(&[&["1", "a", "X"], &["2", "b", "y"], &["3", "c", "z"]]);
+        let merged = merge3(&base, &ours, &theirs);
+        assert_eq!(
+            merged.rows,
+            rows(&[&["1", "A", "X"], &["2", "b", "y"], &["3", "c", "z"]])
+        );
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn keeps_ours_on_conflicting_cells() {
+        let base = rows(&[&["1", "a"], &["2", "b"]]);
+        let ours = rows(&[&["1", "ours"], &["2", "b"]]);
+        let theirs = rows(&[&["1", "theirs"], &["2", "b"]]);
+        let merged = merge3(&base, &ours, &theirs);
+        assert_eq!(merged.rows, rows(&[&["1", "ours"], &["2", "b"]]));
+        assert_eq!(merged.conflicts, 1);
+    }
+
+    #[test]
+    fn keeps_rows_changed_on_one_side_and_removed_on_the_other() {
+        let base = rows(&[&["1", "a"], &["2", "b"], &["3", "c"]]);
+        let ours = rows(&[&["1", "a"], &["3", "c"]]);
+        let theirs = rows(&[&["1", "a"], &["2", "B"], &["3", "c"]]);
+        let merged = merge3(&base, &ours, &theirs);
+        assert_eq!(merged.rows, theirs);
+        assert_eq!(merged.conflicts, 1);
+    }
+
+    #[test]
+    fn counts_keyed_changes() {
+        let header = rows(&[&["id", "v"]]).remove(0);
+        let old = rows(&[&["1", "a"], &["2", "b"]]);
+        let new = rows(&[&["2", "B"], &["3", "c"]]);
+        let diff = diff_tables_by(&header, &old, &header, &new, Some("id"));
+        assert_eq!(diff.counts(), (1, 1, 1));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::error::LoadError;
+use crate::prelude::*;
+use anyhow::bail;
+use encoding_rs::{SHIFT_JIS, UTF_16BE, UTF_16LE};
+use std::fmt::Display;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const UTF16LE_BOM: &[u8] = b"\xFF\xFE";
+const UTF16BE_BOM: &[u8] = b"\xFE\xFF";
+
+/// ファイルの文字コード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf8Bom,
+    ShiftJis,
+    Utf16Le,
+    Utf16Be,
+}
+impl Display for TextEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextEncoding::Utf8 => write!(f, "UTF-8"),
+            TextEncoding::Utf8Bom => write!(f, "UTF-8 (BOM)"),
+            TextEncoding::ShiftJis => write!(f, "Shift_JIS"),
+            TextEncoding::Utf16Le => write!(f, "UTF-16LE"),
+            TextEncoding::Utf16Be => write!(f, "UTF-16BE"),
+        }
+    }
+}
+impl TextEncoding {
+    /// 変換コマンドで順に切り替える文字コード
+    pub const ALL: [TextEncoding; 5] = [
+        TextEncoding::Utf8,
+        TextEncoding::Utf8Bom,
+        TextEncoding::ShiftJis,
+        TextEncoding::Utf16Le,
+        TextEncoding::Utf16Be,
+    ];
+
+    /// BOMとバイト列の妥当性から文字コードを推定する
+    pub fn detect(bytes: &[u8]) -> TextEncoding {
+        if bytes.starts_with(UTF8_BOM) {
+            TextEncoding::Utf8Bom
+        } else if bytes.starts_with(UTF16LE_BOM) {
+            TextEncoding::Utf16Le
+        } else if bytes.starts_with(UTF16BE_BOM) {
+            TextEncoding::Utf16Be
+        } else if std::str::from_utf8(bytes).is_ok() {
+            TextEncoding::Utf8
+        } else if SHIFT_JIS
+            .decode_without_bom_handling_and_without_replacement(bytes)
+            .is_some()
+        {
+            TextEncoding::ShiftJis
+        } else {
+            // どれにも当てはまらなければUTF-8として扱い、decodeでエラーにする
+            TextEncoding::Utf8
+        }
+    }
+    /// 次の文字コード
+    pub fn next(&self) -> TextEncoding {
+        let idx = Self::ALL.iter().position(|e| e == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+    /// バイト列をBOMを除いた文字列に変換する
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, LoadError> {
+        let decoded = match self {
+            TextEncoding::Utf8 => std::str::from_utf8(bytes).ok().map(String::from),
+            TextEncoding::Utf8Bom => {
+                std::str::from_utf8(bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes))
+                    .ok()
+                    .map(String::from)
+            }
+            TextEncoding::ShiftJis => SHIFT_JIS
+                .decode_without_bom_handling_and_without_replacement(bytes)
+                .map(|s| s.into_owned()),
+            TextEncoding::Utf16Le => UTF_16LE
+                .decode_without_bom_handling_and_without_replacement(
+                    bytes.strip_prefix(UTF16LE_BOM).unwrap_or(bytes),
+                )
+                .map(|s| s.into_owned()),
+            TextEncoding::Utf16Be => UTF_16BE
+                .decode_without_bom_handling_and_without_replacement(
+                    bytes.strip_prefix(UTF16BE_BOM).unwrap_or(bytes),
+                )
+                .map(|s| s.into_owned()),
+        };
+        match decoded {
+            Some(text) => Ok(text),
+            None => Err(LoadError::Decode(*self)),
+        }
+    }
+    /// 文字列をBOMを含むバイト列に変換する
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>> {
+        let bytes = match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+            TextEncoding::ShiftJis => {
+                let (bytes, _, had_errors) = SHIFT_JIS.encode(text);
+                if had_errors {
+                    bail!(
+                        "text contains characters that cannot be encoded in {}",
+                        self
+                    );
+                }
+                bytes.into_owned()
+            }
+            // encoding_rsはUTF-16へのエンコードに対応していないため自前で変換する
+            TextEncoding::Utf16Le => UTF16LE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+                .collect(),
+            TextEncoding::Utf16Be => UTF16BE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+                .collect(),
+        };
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_encoding() {
+        assert_eq!(TextEncoding::detect(b"id,name\n"), TextEncoding::Utf8);
+        assert_eq!(
+            TextEncoding::detect(b"\xEF\xBB\xBFid\n"),
+            TextEncoding::Utf8Bom
+        );
+        assert_eq!(TextEncoding::detect(b"\xFF\xFEi\0"), TextEncoding::Utf16Le);
+        assert_eq!(TextEncoding::detect(b"\xFE\xFF\0i"), TextEncoding::Utf16Be);
+        let (sjis, _, _) = SHIFT_JIS.encode("名前,値\n");
+        assert_eq!(TextEncoding::detect(&sjis), TextEncoding::ShiftJis);
+    }
+
+    #[test]
+    fn round_trips_every_encoding() {
+        let text = "id,名前\n1,テスト\n";
+        for encoding in TextEncoding::ALL {
+            let bytes = encoding.encode(text).unwrap();
+            assert_eq!(TextEncoding::detect(&bytes), encoding);
+            assert_eq!(encoding.decode(&bytes).unwrap(), text, "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn refuses_characters_shift_jis_cannot_hold() {
+        assert!(TextEncoding::ShiftJis.encode("😀").is_err());
+    }
+
+    #[test]
+    fn reports_undecodable_bytes() {
+        assert!(TextEncoding::Utf8.decode(b"\xFF\xFF").is_err());
+    }
+}
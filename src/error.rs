@@ -0,0 +1,38 @@
+use crate::encoding::TextEncoding;
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+
+/// テーブル読み込み時のエラー
+#[derive(Debug)]
+pub enum LoadError {
+    /// ファイルを開けない・読めない
+    Io(io::Error),
+    /// 文字コードとして不正なバイト列
+    Decode(TextEncoding),
+    /// CSVとして解釈できない
+    Csv(csv::Error),
+    /// スキーマを宣言するファイルが不正
+    Schema(PathBuf, String),
+}
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Decode(encoding) => write!(f, "invalid {} byte sequence", encoding),
+            LoadError::Csv(e) => write!(f, "{}", e),
+            LoadError::Schema(path, reason) => write!(f, "{}: {}", path.display(), reason),
+        }
+    }
+}
+impl std::error::Error for LoadError {}
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+impl From<csv::Error> for LoadError {
+    fn from(e: csv::Error) -> Self {
+        LoadError::Csv(e)
+    }
+}
@@ -0,0 +1,724 @@
+use crate::prelude::*;
+use chrono::NaiveDate;
+use std::fs;
+
+/// 内容のハッシュで保存したファイルを置く、履歴ディレクトリ内の隠しディレクトリ
+const BLOB_DIR: &str = ".blobs";
+/// これより新しい内容は、参照されていなくても消さない
+const GC_GRACE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// スナップショットの内容を記録するファイル
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+/// マニフェストに記録するテーブルの内容
+#[derive(Debug, Clone)]
+pub struct BlobRef {
+    pub hash: String,
+    pub size: u64,
+    /// このスナップショットで変更されたか
+    pub changed: bool,
+    /// 変更された場合の、追加・削除・変更された行数
+    pub counts: Option<(usize, usize, usize)>,
+}
+
+/// 保存したときのメッセージと保存した人
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotInfo {
+    pub message: String,
+    pub user: String,
+    pub host: String,
+}
+impl SnapshotInfo {
+    /// このプロセスのユーザとホストで作る
+    pub fn current(message: &str) -> SnapshotInfo {
+        SnapshotInfo {
+            message: message.to_string(),
+            user: user_name(),
+            host: host_name(),
+        }
+    }
+}
+
+/// 保存のたびに履歴ディレクトリに作られるスナップショット
+/// マニフェストからハッシュで保存された内容を参照する形式と、CSVをそのまま置いた以前の形式がある
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// フォルダ名（保存日時）
+    pub name: String,
+    pub path: PathBuf,
+    /// 含まれるテーブル
+    pub tables: Vec<OsString>,
+    /// フォルダ自体の大きさ（バイト）
+    pub size: u64,
+    /// マニフェスト形式の場合の、テーブルごとの内容
+    pub blobs: BTreeMap<OsString, BlobRef>,
+    /// マニフェストに記録されたメッセージと保存した人
+    pub info: Option<SnapshotInfo>,
+    /// gitの履歴の場合のコミット
+    pub commit: Option<String>,
+    /// マニフェストを読めなかった場合の理由。テーブルも参照している内容も分からない
+    pub unreadable: Option<String>,
+}
+impl Snapshot {
+    /// スナップショット内のテーブルの内容が保存されているパス
+    pub fn table_path(&self, table_name: &OsString) -> PathBuf {
+        match self.blobs.get(table_name) {
+            Some(blob) => blob_path(self.path.parent().unwrap_or(&self.path), &blob.hash),
+            None => self.path.join(table_name),
+        }
+    }
+    /// このスナップショットで変更されたテーブルか。以前の形式では変更されたテーブルだけが保存されている
+    pub fn is_changed(&self, table_name: &OsString) -> bool {
+        self.blobs.get(table_name).is_none_or(|blob| blob.changed)
+    }
+    /// 変更されたテーブルの数
+    pub fn changed_count(&self) -> usize {
+        self.tables.iter().filter(|t| self.is_changed(t)).count()
+    }
+    /// 参照している内容も含めた大きさ
+    pub fn logical_size(&self) -> u64 {
+        self.size + self.blobs.values().map(|b| b.size).sum::<u64>()
+    }
+}
+
+/// 内容のハッシュから保存先のパスを決める
+pub fn blob_path(archive_dir: &Path, hash: &str) -> PathBuf {
+    archive_dir
+        .join(BLOB_DIR)
+        .join(hash.get(..2).unwrap_or(hash))
+        .join(hash)
+}
+
+/// 内容をハッシュで保存し、そのハッシュを返す。同じ内容が保存済みなら書き込まない
+pub fn store_blob(archive_dir: &Path, content: &[u8]) -> Result<String> {
+    let hash = content_hash(content);
+    let path = blob_path(archive_dir, &hash);
+    if !path.exists() {
+        save_to_file(content, path)?;
+    }
+    Ok(hash)
+}
+
+/// スナップショットのフォルダを新しく作る
+/// 同じ名前のフォルダがあれば使い回さず、名前に番号を付ける
+fn create_snapshot_dir(archive_dir: &Path, name: &str) -> Result<PathBuf> {
+    fs::create_dir_all(archive_dir)?;
+    let mut dir = archive_dir.join(name);
+    let mut n = 1;
+    loop {
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                n += 1;
+                dir = archive_dir.join(format!("{}-{}", name, n));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// テーブルごとの内容を記録したマニフェストを書き、スナップショットのフォルダを返す
+/// 同じ名前のスナップショットがあれば、名前に番号を付けた別のフォルダに書く
+pub fn write_manifest(
+    archive_dir: &Path,
+    name: &str,
+    info: &SnapshotInfo,
+    tables: &BTreeMap<OsString, BlobRef>,
+) -> Result<PathBuf> {
+    let mut entries = toml::Table::new();
+    for (table_name, blob) in tables.iter() {
+        let mut entry = toml::Table::new();
+        entry.insert("blob".to_string(), Value::String(blob.hash.clone()));
+        entry.insert("size".to_string(), Value::Integer(blob.size as i64));
+        entry.insert("changed".to_string(), Value::Boolean(blob.changed));
+        if let Some((added, removed, modified)) = blob.counts {
+            entry.insert("added".to_string(), Value::Integer(added as i64));
+            entry.insert("removed".to_string(), Value::Integer(removed as i64));
+            entry.insert("modified".to_string(), Value::Integer(modified as i64));
+        }
+        entries.insert(
+            table_name.to_string_lossy().into_owned(),
+            Value::Table(entry),
+        );
+    }
+    let mut manifest = toml::Table::new();
+    manifest.insert("version".to_string(), Value::Integer(1));
+    manifest.insert("message".to_string(), Value::String(info.message.clone()));
+    manifest.insert("user".to_string(), Value::String(info.user.clone()));
+    manifest.insert("host".to_string(), Value::String(info.host.clone()));
+    manifest.insert("tables".to_string(), Value::Table(entries));
+
+    let dir = create_snapshot_dir(archive_dir, name)?;
+    if let Err(e) = save_to_file(toml::to_string(&manifest)?, dir.join(MANIFEST_FILE)) {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(e);
+    }
+    Ok(dir)
+}
+
+/// マニフェストを読む
+fn read_manifest(path: &Path) -> Result<(SnapshotInfo, BTreeMap<OsString, BlobRef>)> {
+    let manifest: toml::Table = get_text(path)?
+        .parse()
+        .with_context(|| format!("couldn't parse {}", path.display()))?;
+    let text = |key: &str| {
+        manifest
+            .get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let info = SnapshotInfo {
+        message: text("message"),
+        user: text("user"),
+        host: text("host"),
+    };
+    let mut blobs = BTreeMap::new();
+    let tables = manifest.get("tables").and_then(Value::as_table);
+    for (table_name, entry) in tables.into_iter().flatten() {
+        let count = |key: &str| {
+            entry
+                .get(key)
+                .and_then(Value::as_integer)
+                .map(|v| v as usize)
+        };
+        let hash = entry
+            .get("blob")
+            .and_then(Value::as_str)
+            .with_context(|| format!("{}: blob is missing in {}", table_name, path.display()))?;
+        blobs.insert(
+            OsString::from(table_name),
+            BlobRef {
+                hash: hash.to_string(),
+                size: entry.get("size").and_then(Value::as_integer).unwrap_or(0) as u64,
+                changed: entry
+                    .get("changed")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(true),
+                counts: count("added")
+                    .zip(count("removed"))
+                    .zip(count("modified"))
+                    .map(|((added, removed), modified)| (added, removed, modified)),
+            },
+        );
+    }
+    Ok((info, blobs))
+}
+
+/// 履歴ディレクトリのスナップショットを新しい順に返す
+pub fn list_snapshots(archive_dir: &Path) -> Result<Vec<Snapshot>> {
+    if !archive_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(archive_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        // 内容の保存先などの隠しディレクトリはスナップショットではない
+        if !path.is_dir() || name.starts_with('.') {
+            continue;
+        }
+        let manifest = path.join(MANIFEST_FILE);
+        // 壊れたマニフェストがあっても、他のスナップショットは一覧に出す
+        let mut unreadable = None;
+        let (tables, blobs, info) = match manifest.exists() {
+            true => match read_manifest(&manifest) {
+                Ok((info, blobs)) => (blobs.keys().cloned().collect(), blobs, Some(info)),
+                Err(e) => {
+                    unreadable = Some(format!("{:#}", e));
+                    (Vec::new(), BTreeMap::new(), None)
+                }
+            },
+            false => {
+                let tables = glob(&path.display().to_string(), "csv", false)?
+                    .into_iter()
+                    .filter_map(|p| Path::new(&p).file_name().map(|n| n.to_os_string()))
+                    .collect::<BTreeSet<OsString>>()
+                    .into_iter()
+                    .collect();
+                (tables, BTreeMap::new(), None)
+            }
+        };
+        let size = dir_size(&path)?;
+        snapshots.push(Snapshot {
+            name,
+            path,
+            tables,
+            size,
+            blobs,
+            info,
+            commit: None,
+            unreadable,
+        });
+    }
+    // フォルダ名は日時で始まるので、名前の降順が新しい順になる
+    snapshots.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(snapshots)
+}
+
+/// ディレクトリ以下のファイルの大きさの合計
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(size)
+}
+
+/// 履歴を残す規則。指定のない規則は使わず、どれも指定がなければ何も消さない
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// 新しいものからいくつ残すか
+    pub keep_last: Option<usize>,
+    /// 何日前まで、日ごとに最後のスナップショットを残すか
+    pub keep_daily: Option<i64>,
+    /// 履歴全体の大きさの上限（バイト）。超えたら古いものから消す
+    pub max_bytes: Option<u64>,
+}
+impl Retention {
+    /// 設定の[retention]を読む
+    pub fn from_config(config: &Value) -> Retention {
+        let retention = config.get("retention");
+        let get = |key: &str| {
+            retention
+                .and_then(|r| r.get(key))
+                .and_then(Value::as_integer)
+                .filter(|v| *v > 0)
+        };
+        Retention {
+            keep_last: get("keep_last").map(|v| v as usize),
+            keep_daily: get("keep_daily"),
+            max_bytes: get("max_size_mb").map(|v| v as u64 * 1024 * 1024),
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.keep_last.is_some() || self.keep_daily.is_some() || self.max_bytes.is_some()
+    }
+    /// 規則に従って消すスナップショットを選ぶ。snapshotsは新しい順に並んでいること
+    /// 最新のスナップショットと、マニフェストを読めなかったスナップショットは必ず残す
+    pub fn plan<'a>(&self, snapshots: &'a [Snapshot], today: NaiveDate) -> Vec<&'a Snapshot> {
+        if !self.is_enabled() || snapshots.is_empty() {
+            return Vec::new();
+        }
+        // 件数・日ごとの規則のどちらも指定がなければ、大きさの上限だけで選ぶ
+        let count_rules = self.keep_last.is_some() || self.keep_daily.is_some();
+        let mut keep: Vec<bool> = snapshots
+            .iter()
+            .map(|s| !count_rules || s.unreadable.is_some())
+            .collect();
+        keep[0] = true;
+        if let Some(n) = self.keep_last {
+            keep.iter_mut().take(n).for_each(|k| *k = true);
+        }
+        if let Some(days) = self.keep_daily {
+            let mut seen = BTreeSet::new();
+            for (i, snapshot) in snapshots.iter().enumerate() {
+                let Some(date) = snapshot_date(snapshot) else {
+                    continue;
+                };
+                if (today - date).num_days() < days && seen.insert(date) {
+                    keep[i] = true;
+                }
+            }
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            for i in (1..snapshots.len()).rev() {
+                if stored_size(snapshots, &keep) <= max_bytes {
+                    break;
+                }
+                keep[i] = snapshots[i].unreadable.is_some();
+            }
+        }
+        snapshots
+            .iter()
+            .zip(keep)
+            .filter(|(_, k)| !k)
+            .map(|(s, _)| s)
+            .collect()
+    }
+}
+
+/// 残すスナップショットが使う大きさ。複数のスナップショットが参照する内容は1回だけ数える
+fn stored_size(snapshots: &[Snapshot], keep: &[bool]) -> u64 {
+    let mut blobs = BTreeMap::new();
+    let mut size = 0;
+    for snapshot in snapshots
+        .iter()
+        .zip(keep)
+        .filter(|(_, k)| **k)
+        .map(|(s, _)| s)
+    {
+        size += snapshot.size;
+        for blob in snapshot.blobs.values() {
+            blobs.insert(blob.hash.as_str(), blob.size);
+        }
+    }
+    size + blobs.values().sum::<u64>()
+}
+
+/// planのスナップショットを消したときに空く大きさ
+/// 残すスナップショットも参照している内容は空かないので数えない
+pub fn freed_size(snapshots: &[Snapshot], plan: &[&Snapshot]) -> u64 {
+    let all = vec![true; snapshots.len()];
+    let keep: Vec<bool> = snapshots
+        .iter()
+        .map(|s| !plan.iter().any(|p| p.path == s.path))
+        .collect();
+    stored_size(snapshots, &all) - stored_size(snapshots, &keep)
+}
+
+/// スナップショットを作った日。フォルダ名の日付、読めなければ更新日時を使う
+fn snapshot_date(snapshot: &Snapshot) -> Option<NaiveDate> {
+    snapshot
+        .name
+        .get(..10)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        .or_else(|| {
+            let modified = fs::metadata(&snapshot.path).ok()?.modified().ok()?;
+            Some(DateTime::<Local>::from(modified).date_naive())
+        })
+}
+
+/// スナップショットを消し、どのスナップショットからも参照されなくなった内容も消す。消した数を返す
+pub fn prune(archive_dir: &Path, snapshots: &[&Snapshot]) -> Result<usize> {
+    for snapshot in snapshots.iter() {
+        fs::remove_dir_all(&snapshot.path)
+            .with_context(|| format!("couldn't remove {}", snapshot.path.display()))?;
+    }
+    collect_garbage(archive_dir)?;
+    Ok(snapshots.len())
+}
+
+/// どのスナップショットからも参照されていない内容を消す
+/// 読めないマニフェストが1つでもあれば、どの内容が参照されているか分からないので何も消さない
+fn collect_garbage(archive_dir: &Path) -> Result<()> {
+    let blob_dir = archive_dir.join(BLOB_DIR);
+    if !blob_dir.is_dir() {
+        return Ok(());
+    }
+    let snapshots = list_snapshots(archive_dir)?;
+    if let Some(snapshot) = snapshots.iter().find(|s| s.unreadable.is_some()) {
+        anyhow::bail!(
+            "kept unreferenced contents because the manifest of {} is unreadable: {}",
+            snapshot.name,
+            snapshot.unreadable.as_deref().unwrap_or_default()
+        );
+    }
+    let referenced: BTreeSet<String> = snapshots
+        .into_iter()
+        .flat_map(|s| s.blobs.into_values().map(|b| b.hash))
+        .collect();
+    for prefix in fs::read_dir(&blob_dir)? {
+        let prefix = prefix?.path();
+        if !prefix.is_dir() {
+            continue;
+        }
+        for blob in fs::read_dir(&prefix)? {
+            let blob = blob?.path();
+            let hash = blob.file_name().unwrap().to_string_lossy().into_owned();
+            // 書き込み途中の一時ファイルや、他のプロセスがマニフェストを書く前の内容は触らない
+            let recent = fs::metadata(&blob)?
+                .modified()
+                .ok()
+                .and_then(|m| m.elapsed().ok())
+                .is_none_or(|age| age < GC_GRACE);
+            if !hash.starts_with('.') && !recent && !referenced.contains(&hash) {
+                fs::remove_file(&blob)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 大きさを読みやすい単位で表す
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(name: &str, size: u64) -> Snapshot {
+        Snapshot {
+            name: name.to_string(),
+            path: PathBuf::from(name),
+            tables: Vec::new(),
+            size,
+            blobs: BTreeMap::new(),
+            info: None,
+            commit: None,
+            unreadable: None,
+        }
+    }
+
+    fn names(plan: Vec<&Snapshot>) -> Vec<&str> {
+        plan.into_iter().map(|s| s.name.as_str()).collect()
+    }
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, 18).unwrap()
+    }
+
+    /// テストごとの空の履歴ディレクトリ
+    fn archive_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("crate-history-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// 更新日時を、消してよい古さまで戻す
+    fn age_out(path: &Path) {
+        let old = std::time::SystemTime::now() - GC_GRACE * 2;
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+    }
+
+    fn blob(hash: &str, size: u64) -> BlobRef {
+        BlobRef {
+            hash: hash.to_string(),
+            size,
+            changed: true,
+            counts: None,
+        }
+    }
+
+    #[test]
+    fn lists_snapshot_folders_newest_first() {
+        let dir = archive_dir("list");
+        for (snapshot, table) in [
+            ("2026-10-17-120000", "a.csv"),
+            ("2026-10-17-120000", "b.csv"),
+            ("2026-10-18-090000", "a.csv"),
+        ] {
+            fs::create_dir_all(dir.join(snapshot)).unwrap();
+            fs::write(dir.join(snapshot).join(table), "x").unwrap();
+        }
+        fs::write(dir.join("note.txt"), "").unwrap();
+        let snapshots = list_snapshots(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listed: Vec<(&str, &[OsString])> = snapshots
+            .iter()
+            .map(|s| (s.name.as_str(), s.tables.as_slice()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("2026-10-18-090000", &[OsString::from("a.csv")][..]),
+                (
+                    "2026-10-17-120000",
+                    &[OsString::from("a.csv"), OsString::from("b.csv")][..]
+                ),
+            ]
+        );
+        assert!(list_snapshots(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_nothing_extra_without_rules() {
+        let snapshots = [snapshot("2026-10-18-120000", 1)];
+        assert!(Retention::default().plan(&snapshots, today()).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_last_n() {
+        let snapshots = [
+            snapshot("2026-10-18-120000", 1),
+            snapshot("2026-10-17-120000", 1),
+            snapshot("2026-10-16-120000", 1),
+            snapshot("2026-10-15-120000", 1),
+        ];
+        let retention = Retention {
+            keep_last: Some(2),
+            ..Retention::default()
+        };
+        assert_eq!(
+            names(retention.plan(&snapshots, today())),
+            ["2026-10-16-120000", "2026-10-15-120000"]
+        );
+    }
+
+    #[test]
+    fn keeps_the_newest_of_each_recent_day() {
+        let snapshots = [
+            snapshot("2026-10-18-120000", 1),
+            snapshot("2026-10-18-090000", 1),
+            snapshot("2026-10-17-180000", 1),
+            snapshot("2026-10-17-080000", 1),
+            snapshot("2026-10-10-120000", 1),
+        ];
+        let retention = Retention {
+            keep_daily: Some(2),
+            ..Retention::default()
+        };
+        assert_eq!(
+            names(retention.plan(&snapshots, today())),
+            [
+                "2026-10-18-090000",
+                "2026-10-17-080000",
+                "2026-10-10-120000"
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_the_oldest_over_the_size_limit() {
+        let snapshots = [
+            snapshot("2026-10-18-120000", 10),
+            snapshot("2026-10-17-120000", 10),
+            snapshot("2026-10-16-120000", 10),
+        ];
+        let retention = Retention {
+            max_bytes: Some(25),
+            ..Retention::default()
+        };
+        assert_eq!(
+            names(retention.plan(&snapshots, today())),
+            ["2026-10-16-120000"]
+        );
+    }
+
+    #[test]
+    fn counts_shared_blobs_once_against_the_size_limit() {
+        let mut snapshots = [
+            snapshot("2026-10-18-120000", 1),
+            snapshot("2026-10-17-120000", 1),
+            snapshot("2026-10-16-120000", 1),
+        ];
+        for snapshot in snapshots.iter_mut() {
+            snapshot
+                .blobs
+                .insert(OsString::from("a.csv"), blob("shared", 100));
+        }
+        let retention = Retention {
+            max_bytes: Some(103),
+            ..Retention::default()
+        };
+        assert!(retention.plan(&snapshots, today()).is_empty());
+    }
+
+    #[test]
+    fn collects_only_unreferenced_old_blobs() {
+        let dir = archive_dir("gc");
+        let kept = store_blob(&dir, b"kept").unwrap();
+        let orphan = store_blob(&dir, b"orphan").unwrap();
+        let recent = store_blob(&dir, b"recent").unwrap();
+        age_out(&blob_path(&dir, &kept));
+        age_out(&blob_path(&dir, &orphan));
+        let tables = BTreeMap::from([(OsString::from("a.csv"), blob(&kept, 4))]);
+        write_manifest(&dir, "2026-10-18-120000", &SnapshotInfo::default(), &tables).unwrap();
+
+        let snapshots = list_snapshots(&dir).unwrap();
+        let collected = collect_garbage(&dir);
+        let exists = |hash: &str| blob_path(&dir, hash).exists();
+        let left = (exists(&kept), exists(&orphan), exists(&recent));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].tables, [OsString::from("a.csv")]);
+        assert_eq!(
+            snapshots[0].table_path(&OsString::from("a.csv")),
+            blob_path(&dir, &kept)
+        );
+        assert!(collected.is_ok());
+        assert_eq!(left, (true, false, true));
+    }
+
+    #[test]
+    fn never_plans_to_remove_unreadable_snapshots() {
+        let mut snapshots = [
+            snapshot("2026-10-18-120000", 1),
+            snapshot("2026-10-17-120000", 1),
+            snapshot("2026-10-16-120000", 1),
+        ];
+        snapshots[2].unreadable = Some("broken".to_string());
+        let retention = Retention {
+            keep_last: Some(1),
+            ..Retention::default()
+        };
+        assert_eq!(
+            names(retention.plan(&snapshots, today())),
+            ["2026-10-17-120000"]
+        );
+    }
+
+    #[test]
+    fn keeps_blobs_while_a_manifest_is_unreadable() {
+        let dir = archive_dir("unreadable");
+        let orphan = store_blob(&dir, b"orphan").unwrap();
+        let blob = blob_path(&dir, &orphan);
+        age_out(&blob);
+        let broken = dir.join("2026-10-18-120000");
+        fs::create_dir_all(&broken).unwrap();
+        fs::write(broken.join(MANIFEST_FILE), "tables = [").unwrap();
+
+        let snapshots = list_snapshots(&dir).unwrap();
+        let listed = snapshots.len();
+        let unreadable = snapshots[0].unreadable.is_some();
+        let collected = collect_garbage(&dir);
+        let kept = blob.exists();
+        fs::remove_dir_all(&broken).unwrap();
+        let collected_after = collect_garbage(&dir);
+        let removed = !blob.exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(listed, 1);
+        assert!(unreadable);
+        assert!(collected.is_err());
+        assert!(kept);
+        assert!(collected_after.is_ok());
+        assert!(removed);
+    }
+
+    #[test]
+    fn never_reuses_a_snapshot_folder() {
+        let dir = archive_dir("same-second");
+        let info = SnapshotInfo::default();
+        let tables = BTreeMap::new();
+        let first = write_manifest(&dir, "2026-10-18-120000", &info, &tables).unwrap();
+        let second = write_manifest(&dir, "2026-10-18-120000", &info, &tables).unwrap();
+        let snapshots = list_snapshots(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_ne!(first, second);
+        let names: Vec<&str> = snapshots.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["2026-10-18-120000-2", "2026-10-18-120000"]);
+    }
+
+    #[test]
+    fn counts_only_blobs_no_kept_snapshot_uses_as_freed() {
+        let mut snapshots = [
+            snapshot("2026-10-18-120000", 1),
+            snapshot("2026-10-17-120000", 1),
+        ];
+        for snapshot in snapshots.iter_mut() {
+            snapshot
+                .blobs
+                .insert(OsString::from("a.csv"), blob("shared", 100));
+        }
+        snapshots[1]
+            .blobs
+            .insert(OsString::from("b.csv"), blob("own", 10));
+        assert_eq!(freed_size(&snapshots, &[&snapshots[1]]), 11);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::prelude::*;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+/// ロックファイルに書き込む保持者の情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    pub user: String,
+    pub host: String,
+    pub pid: u32,
+    /// ロックを取った日時
+    pub since: String,
+}
+impl LockOwner {
+    /// このプロセスを保持者とする
+    pub fn current() -> LockOwner {
+        LockOwner {
+            user: user_name(),
+            host: host_name(),
+            pid: std::process::id(),
+            since: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+    /// ロックファイルが読めなかったときの保持者
+    fn unknown() -> LockOwner {
+        LockOwner {
+            user: "unknown".to_string(),
+            host: "unknown".to_string(),
+            pid: 0,
+            since: String::new(),
+        }
+    }
+    pub fn to_toml(&self) -> String {
+        let mut table = toml::map::Map::new();
+        table.insert("user".to_string(), Value::String(self.user.clone()));
+        table.insert("host".to_string(), Value::String(self.host.clone()));
+        table.insert("pid".to_string(), Value::Integer(self.pid as i64));
+        table.insert("since".to_string(), Value::String(self.since.clone()));
+        toml::to_string(&table).unwrap_or_default()
+    }
+    fn parse(text: &str) -> Option<LockOwner> {
+        let value: toml::Table = text.parse().ok()?;
+        Some(LockOwner {
+            user: value.get("user")?.as_str()?.to_string(),
+            host: value.get("host")?.as_str()?.to_string(),
+            pid: value.get("pid")?.as_integer()? as u32,
+            since: value.get("since")?.as_str()?.to_string(),
+        })
+    }
+    fn is_current_process(&self) -> bool {
+        self.pid == std::process::id() && self.host == host_name()
+    }
+    /// 保持者のプロセスが終了していることが確かか
+    /// 同じホストで、プロセスの一覧を確認できる環境（/procがある環境）でのみ判定できる
+    /// /procがない環境では常にfalseとなり、放棄されたロックは古さだけで判断される
+    fn is_dead(&self) -> bool {
+        let proc = Path::new("/proc");
+        self.host == host_name() && proc.is_dir() && !proc.join(self.pid.to_string()).exists()
+    }
+}
+impl Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}@{} (PID {}, {}から)",
+            self.user, self.host, self.pid, self.since
+        )
+    }
+}
+
+/// ロックを取ろうとした結果
+pub enum LockResult {
+    /// ロックを取れた。放棄されたロックを解除した場合はその保持者
+    Acquired {
+        lock: TableLock,
+        broken: Option<LockOwner>,
+    },
+    /// 他のプロセスが保持している
+    HeldBy(LockOwner),
+}
+
+/// テーブルごとの協調的なロック
+/// ロックファイルを作れたプロセスだけがテーブルを編集する。Dropで解除する
+#[derive(Debug)]
+pub struct TableLock {
+    path: PathBuf,
+    owner: LockOwner,
+}
+impl TableLock {
+    /// ロックを取る。保持者が終了している、または`stale_after`以上更新されていないロックは解除して取り直す
+    /// 保持者が終了したかを確かめられない環境（/procがない環境）では、`stale_after`の経過だけで解除する
+    pub fn acquire(path: &Path, stale_after: Duration) -> Result<LockResult> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let owner = LockOwner::current();
+        let mut broken = None;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    file.write_all(owner.to_toml().as_bytes())?;
+                    file.sync_all()?;
+                    let lock = TableLock {
+                        path: path.to_path_buf(),
+                        owner,
+                    };
+                    return Ok(LockResult::Acquired { lock, broken });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            // 既存のロックが放棄されたものか確かめる。一度解除しても取れなければ諦める
+            // 読めないロックファイルは書き込み途中かもしれないので、古さだけで判断する
+            let holder = read_owner(path);
+            if !is_stale(path, &holder, stale_after) || broken.is_some() {
+                let holder = holder.unwrap_or_else(LockOwner::unknown);
+                return Ok(LockResult::HeldBy(holder));
+            }
+            if !break_lock(path, &holder, stale_after)? {
+                // 確かめている間に他のプロセスが取り直した
+                let holder = read_owner(path).unwrap_or_else(LockOwner::unknown);
+                return Ok(LockResult::HeldBy(holder));
+            }
+            broken = Some(holder.unwrap_or_else(LockOwner::unknown));
+        }
+    }
+    /// まだこのプロセスがロックを保持しているか（放棄されたとみなされて解除されていないか）
+    pub fn is_held(&self) -> bool {
+        read_owner(&self.path).is_some_and(|owner| owner == self.owner)
+    }
+    /// ロックファイルを書き直して、使用中であることを示す
+    pub fn refresh(&self) -> Result<()> {
+        if !self.is_held() {
+            anyhow::bail!("lock {} is no longer held", self.path.display());
+        }
+        save_to_file(self.owner.to_toml(), self.path.clone())
+    }
+}
+impl Drop for TableLock {
+    fn drop(&mut self) {
+        if self.is_held() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// ロックファイルの保持者。読めない場合はNone
+fn read_owner(path: &Path) -> Option<LockOwner> {
+    get_text(path).ok().and_then(|text| LockOwner::parse(&text))
+}
+
+/// holderが保持するロックファイルが放棄されたものか
+fn is_stale(path: &Path, holder: &Option<LockOwner>, stale_after: Duration) -> bool {
+    holder
+        .as_ref()
+        .is_some_and(|h| h.is_dead() || h.is_current_process())
+        || age(path).is_some_and(|age| age >= stale_after)
+}
+
+/// 放棄されたと判断したロックファイルを消す。消せた（既になかった）場合はtrue
+/// 判断してから消すまでに他のプロセスが取り直したロックを消さないよう、別名に移してから保持者を確かめ直し、
+/// 別のロックだったら元に戻してfalseを返す
+fn break_lock(path: &Path, holder: &Option<LockOwner>, stale_after: Duration) -> Result<bool> {
+    let mut moved = path.as_os_str().to_os_string();
+    moved.push(format!(".broken-{}", std::process::id()));
+    let moved = PathBuf::from(moved);
+    match fs::rename(path, &moved) {
+        Ok(()) => {}
+        // 他のプロセスが先に解除した
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    }
+    if read_owner(&moved) == *holder && is_stale(&moved, holder, stale_after) {
+        fs::remove_file(&moved)?;
+        return Ok(true);
+    }
+    // 元の名前に戻す。その間にさらに別のプロセスがロックを取っていれば、そちらを残す
+    let restored = fs::hard_link(&moved, path);
+    fs::remove_file(&moved)?;
+    match restored {
+        Ok(()) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 保持者の情報を書いたファイルから、それを書いたプロセスが放棄したとみなせるかを判定する
+/// ロックと同じく、保持者が終了している、またはファイルが`stale_after`以上更新されていなければ放棄されたとする
+pub fn is_abandoned(owner_path: &Path, stale_after: Duration) -> bool {
+    read_owner(owner_path).is_some_and(|owner| owner.is_dead())
+        || age(owner_path).is_some_and(|age| age >= stale_after)
+}
+
+/// ロックファイルが最後に更新されてからの時間
+fn age(path: &Path) -> Option<Duration> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    SystemTime::now().duration_since(modified).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("crate-locks-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// このホストの、指定のプロセスを保持者とするロックファイルを置く
+    fn hold(path: &Path, pid: u32) -> LockOwner {
+        let owner = LockOwner {
+            pid,
+            ..LockOwner::current()
+        };
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, owner.to_toml()).unwrap();
+        owner
+    }
+
+    const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn acquires_a_free_lock_and_releases_it_on_drop() {
+        let path = lock_path("free.lock");
+        let result = TableLock::acquire(&path, STALE_AFTER).unwrap();
+        let LockResult::Acquired { lock, broken } = result else {
+            panic!("lock was not acquired");
+        };
+        assert!(broken.is_none());
+        assert!(lock.is_held());
+        assert_eq!(read_owner(&path), Some(lock.owner.clone()));
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_a_live_holder() {
+        let path = lock_path("live.lock");
+        let owner = hold(&path, std::os::unix::process::parent_id());
+        let result = TableLock::acquire(&path, STALE_AFTER).unwrap();
+        let _ = fs::remove_file(&path);
+        match result {
+            LockResult::HeldBy(holder) => assert_eq!(holder, owner),
+            LockResult::Acquired { .. } => panic!("took a lock held by a live process"),
+        }
+    }
+
+    #[test]
+    fn breaks_a_lock_whose_holder_has_exited() {
+        let path = lock_path("dead.lock");
+        if !Path::new("/proc").is_dir() {
+            return;
+        }
+        let owner = hold(&path, u32::MAX / 2);
+        let result = TableLock::acquire(&path, STALE_AFTER).unwrap();
+        let LockResult::Acquired { lock, broken } = result else {
+            panic!("lock of an exited process was not broken");
+        };
+        assert_eq!(broken, Some(owner));
+        assert!(lock.is_held());
+    }
+
+    #[test]
+    fn breaks_an_unreadable_lock_only_by_age() {
+        let path = lock_path("old.lock");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "not a lock").unwrap();
+        let fresh = TableLock::acquire(&path, STALE_AFTER).unwrap();
+        assert!(matches!(fresh, LockResult::HeldBy(_)));
+        let old = TableLock::acquire(&path, Duration::ZERO).unwrap();
+        let LockResult::Acquired { lock, broken } = old else {
+            panic!("old lock was not broken");
+        };
+        assert_eq!(broken, Some(LockOwner::unknown()));
+        drop(lock);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notices_when_the_lock_was_taken_over() {
+        let path = lock_path("taken.lock");
+        let LockResult::Acquired { lock, .. } = TableLock::acquire(&path, STALE_AFTER).unwrap()
+        else {
+            panic!("lock was not acquired");
+        };
+        hold(&path, std::os::unix::process::parent_id());
+        assert!(!lock.is_held());
+        assert!(lock.refresh().is_err());
+        drop(lock);
+        // 他の保持者のロックファイルは消さない
+        assert!(path.exists());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_a_lock_taken_again_while_breaking_it() {
+        let path = lock_path("retaken.lock");
+        let dead = LockOwner {
+            pid: u32::MAX / 2,
+            ..LockOwner::current()
+        };
+        // 放棄されたと判断した後に、他のプロセスが取り直した状態
+        let live = hold(&path, std::os::unix::process::parent_id());
+        let broken = break_lock(&path, &Some(dead), STALE_AFTER).unwrap();
+        let left = read_owner(&path);
+        let moved = path.with_file_name(format!("retaken.lock.broken-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        assert!(!broken);
+        assert_eq!(left, Some(live));
+        assert!(!moved.exists());
+    }
+}
@@ -3,8 +3,12 @@
 
 mod prelude {
     pub use crate::data_reader::get_string_records;
-    pub use crate::utils::{copy_recursive, get_text, glob, save_to_file};
-    pub use anyhow::Result;
+    pub use crate::data_writer::write_string_records;
+    pub use crate::utils::{
+        content_hash, copy_recursive, file_hash, get_bytes, get_text, glob, host_name,
+        save_files_atomically, save_to_file, user_name, FileStamp,
+    };
+    pub use anyhow::{Context, Result};
     pub use chrono::{DateTime, Local};
     pub use std::collections::{BTreeMap, BTreeSet};
     pub use std::ffi::OsString;
@@ -13,12 +17,24 @@ mod prelude {
     pub use toml::Value;
 }
 
+mod archive;
 mod config;
 mod controller;
 mod data_reader;
+mod data_writer;
+mod dialect;
+mod diff;
+mod encoding;
+mod error;
+mod history;
+mod lock;
 mod model;
+mod row_store;
+mod schema;
+mod temporal;
 mod ui;
 mod utils;
+mod validate;
 use crate::prelude::*;
 use crossterm::{
     event::{
@@ -28,6 +44,7 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use std::panic::{self, AssertUnwindSafe};
 pub use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Alignment, Constraint, Direction, Layout, Rect},
@@ -46,6 +63,9 @@ fn main() -> Result<()> {
 }
 
 pub fn run_app(config: Value) -> Result<()> {
+    // ターミナルをセットアップする前に、設定の誤りなどはここでエラーにする
+    let mut app = controller::App::new(&config)?;
+
     // ターミナルのセットアップ
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -58,22 +78,44 @@ pub fn run_app(config: Value) -> Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let mut app = controller::App::new(&config);
-    let res = app.run(&mut terminal);
+    // パニック時もターミナルを元に戻してからメッセージを表示する
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        default_hook(info);
+    }));
+
+    let res = panic::catch_unwind(AssertUnwindSafe(|| app.run(&mut terminal)));
 
     // ターミナルをrawモードから切り替え
+    restore_terminal()?;
+    terminal.show_cursor()?;
+
+    // エラーやパニックで終了した場合は編集中のテーブルを退避し、0以外で終了する
+    let err = match res {
+        Ok(Ok(())) => return Ok(()),
+        Ok(Err(err)) => err,
+        // パニックのメッセージはフックで表示済み
+        Err(_) => anyhow::anyhow!("the editor panicked"),
+    };
+    let dumped = app.dump_for_recovery();
+    if !dumped.is_empty() {
+        println!("unsaved tables were written to:");
+        for path in dumped {
+            println!("  {}", path.display());
+        }
+    }
+    Err(err)
+}
+
+/// rawモードと代替スクリーンを解除する
+fn restore_terminal() -> Result<()> {
     disable_raw_mode()?;
     execute!(
-        terminal.backend_mut(),
+        io::stdout(),
         LeaveAlternateScreen,
         DisableMouseCapture,
         PopKeyboardEnhancementFlags
     )?;
-    terminal.show_cursor()?;
-
-    if let Err(err) = res {
-        println!("{:?}", err)
-    }
-
     Ok(())
 }
@@ -1,16 +1,33 @@
+use crate::dialect::Dialect;
+use crate::diff::merge3;
+use crate::encoding::TextEncoding;
+use crate::error::LoadError;
 use crate::prelude::*;
+use crate::row_store::RowStore;
+use crate::schema::{read_sidecar, ColumnSpec};
+use crate::temporal::{infer_temporal, parse_date, parse_datetime, parse_datetime_tz, parse_time};
+use crate::validate::Rules;
 
 use regex::{Regex, RegexBuilder};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Display;
+use std::str::FromStr;
 pub use tui::widgets::{ListState, TableState};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DataType {
     Utf8,
     Int64,
     Float64,
     Boolean,
+    Date,
+    /// オフセットのない日時
+    DateTime,
+    /// オフセット付きの日時
+    DateTimeTz,
+    Time,
+    /// 決まった値のどれかをとる列。値の一覧を持つ
+    Enum(Vec<String>),
     Unknown,
 }
 impl Display for DataType {
@@ -28,6 +45,21 @@ impl Display for DataType {
             DataType::Utf8 => {
                 write!(f, "String")
             }
+            DataType::Date => {
+                write!(f, "Date")
+            }
+            DataType::DateTime => {
+                write!(f, "DateTime")
+            }
+            DataType::DateTimeTz => {
+                write!(f, "DateTimeTz")
+            }
+            DataType::Time => {
+                write!(f, "Time")
+            }
+            DataType::Enum(_) => {
+                write!(f, "Enum")
+            }
             DataType::Unknown => {
                 write!(f, "Unknown")
             }
@@ -35,16 +67,167 @@ impl Display for DataType {
     }
 }
 
+/// スキーマを宣言するファイルの型名。表示名のほか、小文字やRustの型名も受け付ける
+impl FromStr for DataType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "string" | "utf8" | "str" => Ok(DataType::Utf8),
+            "int" | "int64" | "integer" => Ok(DataType::Int64),
+            "float" | "float64" | "double" => Ok(DataType::Float64),
+            "boolean" | "bool" => Ok(DataType::Boolean),
+            "date" => Ok(DataType::Date),
+            "datetime" => Ok(DataType::DateTime),
+            "datetimetz" | "datetime_tz" => Ok(DataType::DateTimeTz),
+            "time" => Ok(DataType::Time),
+            // 値の一覧はスキーマを宣言するファイルの values から読む
+            "enum" => Ok(DataType::Enum(Vec::new())),
+            _ => Err(format!("unknown type \"{}\"", s)),
+        }
+    }
+}
+impl DataType {
+    /// 値がこの型として読めるか。空の値はどの型でも受け付ける
+    pub fn accepts(&self, value: &str) -> bool {
+        let value = value.trim();
+        if value.is_empty() {
+            return true;
+        }
+        match self {
+            DataType::Int64 => value.parse::<i64>().is_ok(),
+            DataType::Float64 => value.parse::<f64>().is_ok(),
+            DataType::Boolean => {
+                value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false")
+            }
+            DataType::Date => parse_date(value).is_some(),
+            DataType::DateTime => parse_datetime(value).is_some(),
+            DataType::DateTimeTz => parse_datetime_tz(value).is_some(),
+            DataType::Time => parse_time(value).is_some(),
+            DataType::Enum(values) => values.iter().any(|v| v == value),
+            DataType::Utf8 | DataType::Unknown => true,
+        }
+    }
+    /// 推論中の列の型に、新しく見た値の型を合わせた型
+    /// 整数と小数は小数に、日付と日時は日時にまとめ、合わないものは文字列にする
+    fn widen(&self, other: &DataType) -> DataType {
+        match (self, other) {
+            (DataType::Unknown, t) | (t, DataType::Unknown) => t.clone(),
+            (a, b) if a == b => a.clone(),
+            (DataType::Int64, DataType::Float64) | (DataType::Float64, DataType::Int64) => {
+                DataType::Float64
+            }
+            // 真偽値は数値の列に混ざっていても数値とする
+            (DataType::Boolean, t @ (DataType::Int64 | DataType::Float64))
+            | (t @ (DataType::Int64 | DataType::Float64), DataType::Boolean) => t.clone(),
+            (DataType::Date, DataType::DateTime) | (DataType::DateTime, DataType::Date) => {
+                DataType::DateTime
+            }
+            _ => DataType::Utf8,
+        }
+    }
+}
+
+/// 値の種類がこれ以下の文字列の列はEnumと推論する
+const ENUM_MAX_VARIANTS: usize = 10;
+/// Enumと推論するのに必要な、NULLでない値の数
+const ENUM_MIN_VALUES: usize = 20;
+
+/// 値がないこと（NULL）を表す文字列
+/// 空文字を含めなければ、空文字は空の文字列として扱う
+#[derive(Debug, Clone)]
+pub struct NullTokens {
+    tokens: Vec<String>,
+    /// 値をNULLにするときに書き込む文字列
+    write: String,
+}
+impl Default for NullTokens {
+    fn default() -> Self {
+        NullTokens {
+            tokens: ["\\N", "NULL", "NA"].map(String::from).to_vec(),
+            write: "\\N".to_string(),
+        }
+    }
+}
+impl NullTokens {
+    /// 設定の[null]を読む。書き込む文字列がNULLを表す文字列になければ加える
+    pub fn from_config(config: &Value) -> Result<NullTokens> {
+        let null = config.get("null");
+        let default = NullTokens::default();
+        let tokens = match null.and_then(|n| n.get("tokens")) {
+            Some(tokens) => tokens
+                .as_array()
+                .and_then(|tokens| {
+                    tokens
+                        .iter()
+                        .map(|t| t.as_str().map(String::from))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .context("null.tokens must be an array of strings")?,
+            None => default.tokens,
+        };
+        let write = match null.and_then(|n| n.get("write")) {
+            Some(write) => write
+                .as_str()
+                .context("null.write must be a string")?
+                .to_string(),
+            None => tokens.first().cloned().unwrap_or_default(),
+        };
+        let mut nulls = NullTokens { tokens, write };
+        if !nulls.is_null(&nulls.write) {
+            nulls.tokens.push(nulls.write.clone());
+        }
+        Ok(nulls)
+    }
+    pub fn is_null(&self, value: &str) -> bool {
+        self.tokens.iter().any(|t| t == value)
+    }
+    /// 値をNULLにするときに書き込む文字列
+    pub fn token(&self) -> &str {
+        &self.write
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Column {
     pub name: String,
     pub data_type: DataType,
+    /// NULLを許すか
+    pub nullable: bool,
+    pub description: Option<String>,
+    /// スキーマを宣言するファイルで宣言された列か。宣言された列は型推論しない
+    pub declared: bool,
+    /// 宣言された値の規則
+    pub rules: Rules,
+}
+impl Column {
+    /// 値がこの列の型・規則に合わなければ、その理由を返す
+    pub fn check(&self, value: &str, nulls: &NullTokens) -> Option<String> {
+        if nulls.is_null(value) {
+            return (!self.nullable).then(|| "値が必要です".to_string());
+        }
+        // 推論したEnumの値の一覧は推論に使った行の値にすぎないので、一覧にない値も許す
+        let accepted = match &self.data_type {
+            DataType::Enum(_) if !self.declared => true,
+            data_type => data_type.accepts(value),
+        };
+        if !accepted {
+            return Some(format!(
+                "\"{}\" は {} として読めません",
+                value, self.data_type
+            ));
+        }
+        self.rules.check(value)
+    }
 }
 impl Default for Column {
     fn default() -> Self {
         Self {
             name: String::new(),
             data_type: DataType::Utf8,
+            nullable: true,
+            description: None,
+            declared: false,
+            rules: Rules::default(),
         }
     }
 }
@@ -54,20 +237,45 @@ pub struct TableSchema {
     pub columns: Vec<Column>,
 }
 impl TableSchema {
-    fn push(mut self, new_column: Column) {
+    fn push(&mut self, new_column: Column) {
         self.columns.push(new_column);
     }
-    fn remove(mut self, index: usize) {
+    fn remove(&mut self, index: usize) {
         self.columns.remove(index);
     }
 }
 
+/// 最後に読み込んだ・保存した時点の内容。外部で変更されたファイルとのマージに使う
+#[derive(Debug, Clone)]
+pub struct BaseContent {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
 #[derive(Debug)]
 pub struct DataTable {
     pub state: TableState,
     pub rows_selected: BTreeSet<usize>,
+    /// 選択中の列。選択中の行のこの列のセルを強調し、行の編集はこの列から始める
+    pub selected_column: Option<usize>,
     pub schema: TableSchema,
-    pub values: Vec<Vec<String>>,
+    pub rows: RowStore,
+    /// 表示している先頭行のindex
+    pub offset: usize,
+    pub dialect: Dialect,
+    pub encoding: TextEncoding,
+    /// 編集のたびに増える世代番号。自動保存の要否の判定に使う
+    pub generation: u64,
+    /// 最後に保存してからの編集回数
+    pub change_count: usize,
+    /// 読み込んだ・保存した時点のファイルの状態
+    pub origin: Option<FileStamp>,
+    /// 読み込んだ・保存した時点の内容。索引で読んでいるテーブルは持たない
+    pub base: Option<BaseContent>,
+    /// 読み取り専用で開いている理由。編集画面にバナーとして表示する
+    pub read_only: Option<String>,
+    /// 値がないことを表す文字列
+    pub nulls: NullTokens,
 }
 impl DataTable {
     pub fn new<S>(data: Vec<Vec<S>>) -> DataTable
@@ -78,14 +286,44 @@ impl DataTable {
         let mut data_iter = data.into_iter();
 
         //最初の行はヘッダと想定して取得
-        let header = data_iter.next().unwrap();
+        let header = data_iter.next().unwrap_or_default();
+        let header = header.into_iter().map(Into::into).collect();
+
+        //値の作成 （data_iterの２行目以降）
+        let mut values = Vec::new();
+        for v in data_iter {
+            let mut row: Vec<String> = Vec::new();
+            for vv in v {
+                let string_value = vv.into();
+                row.push(string_value);
+            }
+            values.push(row);
+        }
 
+        DataTable::with_rows(header, RowStore::from_rows(values))
+    }
+    /// CSVファイルを読み込んでテーブルを作る
+    pub fn load(path: &Path) -> Result<DataTable, LoadError> {
+        let source = get_string_records(path)?;
+        let mut data_table = DataTable::with_rows(source.header, source.rows);
+        data_table.dialect = source.dialect;
+        data_table.encoding = source.encoding;
+        data_table.origin = FileStamp::read(path).ok();
+        data_table.remember_base();
+        if let Some(specs) = read_sidecar(path)? {
+            data_table.apply_declared(&specs);
+        }
+        Ok(data_table)
+    }
+    /// ヘッダと行データからテーブルを作る
+    pub fn with_rows(header: Vec<String>, rows: RowStore) -> DataTable {
         // 初期スキーマのcolumnsを作成
         let mut columns = Vec::new();
         for col_name in header {
             columns.push(Column {
-                name: col_name.into(),
+                name: col_name,
                 data_type: DataType::Unknown,
+                ..Column::default()
             });
         }
 
@@ -94,32 +332,118 @@ impl DataTable {
             columns,
         };
 
-        //値の作成 （data_iterの２行目以降）
-        let mut values = Vec::new();
-        for v in data_iter {
-            let mut row: Vec<String> = Vec::new();
-            for vv in v {
-                let string_value = vv.into();
-                row.push(string_value);
-            }
-            values.push(row);
-        }
-
         let mut return_value = DataTable {
             state: TableState::default(),
             rows_selected: BTreeSet::new(),
+            selected_column: None,
             schema: initial_schema,
-            values,
+            rows,
+            offset: 0,
+            dialect: Dialect::default(),
+            encoding: TextEncoding::Utf8,
+            generation: 0,
+            change_count: 0,
+            origin: None,
+            base: None,
+            read_only: None,
+            nulls: NullTokens::default(),
         };
 
         // 型推論
         return_value.infer_schema(Some(100));
         return_value
     }
+    /// NULLを表す文字列を変え、それに合わせて型を推論し直す
+    pub fn set_null_tokens(&mut self, nulls: NullTokens) {
+        self.nulls = nulls;
+        self.infer_schema(Some(100));
+    }
+    /// 宣言されたスキーマを列名で対応付けて適用する。推論した型より優先する
+    /// テーブルにない列の宣言は無視する
+    pub fn apply_declared(&mut self, specs: &[ColumnSpec]) {
+        for column in self.schema.columns.iter_mut() {
+            let Some(spec) = specs.iter().find(|s| s.name == column.name) else {
+                continue;
+            };
+            column.data_type = spec.data_type.clone();
+            column.nullable = spec.nullable;
+            column.description = spec.description.clone();
+            column.rules = spec.rules.clone();
+            column.declared = true;
+        }
+    }
+    /// 現在のスキーマを宣言の形で返す
+    pub fn column_specs(&self) -> Vec<ColumnSpec> {
+        self.schema
+            .columns
+            .iter()
+            .map(ColumnSpec::from_column)
+            .collect()
+    }
+    /// 編集されたことを記録する
+    pub fn touch(&mut self) {
+        self.generation += 1;
+        self.change_count += 1;
+    }
+    /// 最後に保存してから編集されているか
+    pub fn is_modified(&self) -> bool {
+        self.change_count > 0
+    }
+    /// 保存されたことを記録する
+    pub fn mark_saved(&mut self) {
+        self.change_count = 0;
+        self.remember_base();
+    }
+    /// 現在の内容をマージの基準として覚えておく
+    fn remember_base(&mut self) {
+        self.base = match self.rows.is_indexed() {
+            true => None,
+            false => self.to_rows().ok().map(|rows| BaseContent {
+                header: self.header(),
+                rows,
+            }),
+        };
+    }
+    /// 外部で変更されたファイルの内容に、最後に読み込んでからの編集を反映する
+    /// 両方で変更されたセルは編集中の値を優先し、その数を返す
+    pub fn merge_from(&mut self, theirs: &DataTable) -> Result<usize> {
+        let base = self
+            .base
+            .as_ref()
+            .context("the original content is not kept for this table")?;
+        if theirs.header() != base.header || self.schema.columns.len() != base.header.len() {
+            anyhow::bail!("columns were changed; merging is not supported");
+        }
+        let theirs_rows = theirs.to_rows()?;
+        let merged = merge3(&base.rows, &self.to_rows()?, &theirs_rows);
+        self.rows = RowStore::from_rows(merged.rows);
+        self.rows_selected.clear();
+        if self.state.selected().is_some_and(|i| i >= self.rows.len()) {
+            self.state.select(None);
+        }
+        self.base = Some(BaseContent {
+            header: theirs.header(),
+            rows: theirs_rows,
+        });
+        self.origin = theirs.origin.clone();
+        self.touch();
+        Ok(merged.conflicts)
+    }
+    /// ヘッダ行
+    pub fn header(&self) -> Vec<String> {
+        self.schema.columns.iter().map(|c| c.name.clone()).collect()
+    }
+    /// 全行をメモリ上に読み出す
+    pub fn to_rows(&self) -> Result<Vec<Vec<String>>> {
+        self.rows
+            .iter()
+            .map(|row| row.map(|r| r.into_owned()))
+            .collect()
+    }
     pub fn next(&mut self) {
         let i = match self.state.selected() {
             Some(i) => {
-                if i + 1 >= self.values.len() {
+                if i + 1 >= self.rows.len() {
                     0
                 } else {
                     i + 1
@@ -127,7 +451,7 @@ impl DataTable {
             }
             None => 0,
         };
-        if self.values.is_empty() {
+        if self.rows.is_empty() {
             self.add_row();
             self.state.select(Some(0));
         } else {
@@ -135,10 +459,13 @@ impl DataTable {
         }
     }
     pub fn previous(&mut self) {
+        if self.rows.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
-                    self.values.len() - 1
+                    self.rows.len() - 1
                 } else {
                     i - 1
                 }
@@ -147,6 +474,28 @@ impl DataTable {
         };
         self.state.select(Some(i));
     }
+    /// 右の列を選択する。右端からは左端に戻る
+    pub fn next_column(&mut self) {
+        let len = self.schema.columns.len();
+        if len == 0 {
+            return;
+        }
+        self.selected_column = Some(match self.selected_column {
+            Some(i) if i + 1 < len => i + 1,
+            _ => 0,
+        });
+    }
+    /// 左の列を選択する。左端からは右端に移る
+    pub fn previous_column(&mut self) {
+        let len = self.schema.columns.len();
+        if len == 0 {
+            return;
+        }
+        self.selected_column = Some(match self.selected_column {
+            Some(i) if i > 0 && i < len => i - 1,
+            _ => len - 1,
+        });
+    }
     pub fn infer_field_type(&self, string: &str) -> DataType {
         let empty_re = Regex::new("^$").unwrap();
 
@@ -162,7 +511,7 @@ impl DataTable {
         let integer_re = Regex::new(r"^\s*-?(\d+)$").unwrap();
 
         // 特定順序でregexを適用して合致する型を探す
-        if empty_re.is_match(string) {
+        if empty_re.is_match(string) || self.nulls.is_null(string) {
             DataType::Unknown
         } else if boolean_re.is_match(string) {
             DataType::Boolean
@@ -170,6 +519,8 @@ impl DataTable {
             DataType::Float64
         } else if integer_re.is_match(string) {
             DataType::Int64
+        } else if let Some(dtype) = infer_temporal(string) {
+            dtype
         } else {
             DataType::Utf8
         }
@@ -177,81 +528,98 @@ impl DataTable {
     pub fn infer_schema(&mut self, max_read_lines: Option<usize>) {
         // 推論に使用するライン数を全行数か設定行数にする
         let len = match max_read_lines {
-            Some(v) => std::cmp::min(v, self.values.len()),
-            None => self.values.len(),
+            Some(v) => std::cmp::min(v, self.rows.len()),
+            None => self.rows.len(),
         };
 
         let mut field_dtypes = BTreeMap::<String, DataType>::new();
+        // 列ごとのNULLでない値の数と、値の種類。種類が多すぎる列は数えるのをやめる
+        let mut distinct = BTreeMap::<String, (usize, Option<BTreeSet<String>>)>::new();
 
-        for row in &self.values[0..len] {
+        // 読み出せない行は推論に使わない
+        for row in self.rows.iter().take(len).flatten() {
             for (val, col) in row.iter().zip(self.schema.columns.iter()) {
                 let dtype = self.infer_field_type(val);
                 let col_name = &col.name;
-                match dtype {
-                    DataType::Utf8 => match field_dtypes.get(col_name) {
-                        Some(DataType::Utf8) => (),
-                        _ => {
-                            field_dtypes.insert(col_name.to_owned(), dtype);
+                if !self.nulls.is_null(val) {
+                    let (count, values) = distinct
+                        .entry(col_name.to_owned())
+                        .or_insert_with(|| (0, Some(BTreeSet::new())));
+                    *count += 1;
+                    if let Some(set) = values {
+                        set.insert(val.to_owned());
+                        if set.len() > ENUM_MAX_VARIANTS {
+                            *values = None;
                         }
-                    },
-                    DataType::Int64 => match field_dtypes.get(col_name) {
-                        Some(DataType::Utf8) => (),
-                        Some(DataType::Int64) => (),
-                        _ => {
-                            field_dtypes.insert(col_name.to_owned(), dtype);
-                        }
-                    },
-                    DataType::Float64 => match field_dtypes.get(col_name) {
-                        Some(DataType::Utf8) => (),
-                        Some(DataType::Int64) => (),
-                        Some(DataType::Float64) => (),
-                        _ => {
-                            field_dtypes.insert(col_name.to_owned(), dtype);
-                        }
-                    },
-                    DataType::Boolean => match field_dtypes.get(col_name) {
-                        Some(DataType::Utf8) => (),
-                        Some(DataType::Float64) => (),
-                        Some(DataType::Int64) => (),
-                        Some(DataType::Boolean) => (),
-                        Some(DataType::Unknown) => {
-                            field_dtypes.insert(col_name.to_owned(), dtype);
-                        }
-                        None => {
-                            field_dtypes.insert(col_name.to_owned(), dtype);
-                        }
-                    },
-                    DataType::Unknown => (),
+                    }
                 }
+                let widened = match field_dtypes.get(col_name) {
+                    Some(current) => current.widen(&dtype),
+                    None => dtype,
+                };
+                field_dtypes.insert(col_name.to_owned(), widened);
             }
         }
 
-        for c in self.schema.columns.iter_mut() {
-            match field_dtypes.get(&c.name) {
-                Some(d) => {
-                    c.data_type = d.clone();
-                }
-                None => c.data_type = DataType::Unknown,
+        // 値の種類が少なく、同じ値が繰り返し現れる文字列の列はEnumとする
+        for (col_name, (count, values)) in distinct {
+            let Some(values) = values else {
+                continue;
+            };
+            if field_dtypes.get(&col_name) == Some(&DataType::Utf8)
+                && count >= ENUM_MIN_VALUES
+                && values.len() * 2 <= count
+            {
+                field_dtypes.insert(col_name, DataType::Enum(values.into_iter().collect()));
             }
         }
+
+        // 宣言された列はそのままにする
+        // NULLを許さないのは宣言された列だけで、推論した列はNULLを許す
+        for c in self.schema.columns.iter_mut().filter(|c| !c.declared) {
+            c.nullable = true;
+            c.data_type = field_dtypes
+                .get(&c.name)
+                .cloned()
+                .unwrap_or(DataType::Unknown);
+        }
     }
     pub fn add_row(&mut self) {
-        let new_line = vec!["".to_owned(); self.schema.columns.len()]; // TODO: スキーマに沿ったデフォルト値生成
-        self.values.push(new_line);
+        // 新しい行の値はNULLにする
+        let new_line = vec![self.nulls.token().to_owned(); self.schema.columns.len()];
+        self.rows.push(new_line);
+        self.touch();
     }
-    pub fn add_column(self) {
-        self.schema.push(Column::default());
+    /// 末尾に列を追加し、既存の行をNULLで埋める
+    pub fn add_column(&mut self, name: String) {
+        self.schema.push(Column {
+            name,
+            ..Column::default()
+        });
+        self.rows
+            .set_width(self.schema.columns.len(), self.nulls.token());
+        self.touch();
     }
-    pub fn text(&self) -> String {
-        // TODO: ナイーブ
-        let text = self
-            .values
+    /// スキーマのヘッダ行を先頭に付けたCSV文字列を返す
+    pub fn text(&self) -> Result<String> {
+        let header: Vec<&str> = self
+            .schema
+            .columns
             .iter()
-            .map(|record| record.join(", "))
-            .collect::<Vec<String>>()
-            .join("\n");
-
-        text
+            .map(|c| c.name.as_str())
+            .collect();
+        write_string_records(&header, self.rows.iter(), &self.dialect)
+    }
+    /// 保存用に、元の文字コードでエンコードしたバイト列を返す
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        self.encoding.encode(&self.text()?)
+    }
+    /// 文字コードを変更する。変換できない文字があれば変更しない
+    pub fn convert_encoding(&mut self, encoding: TextEncoding) -> Result<()> {
+        encoding.encode(&self.text()?)?;
+        self.encoding = encoding;
+        self.touch();
+        Ok(())
     }
 }
 
@@ -268,6 +636,9 @@ impl<T> StatefulList<T> {
     }
 
     pub fn next(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i >= self.items.len() - 1 {
@@ -282,6 +653,9 @@ impl<T> StatefulList<T> {
     }
 
     pub fn previous(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
@@ -0,0 +1,333 @@
+use crate::dialect::Dialect;
+use crate::error::LoadError;
+use crate::prelude::*;
+use csv::ByteRecord;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+/// 1行分の実体
+#[derive(Debug, Clone)]
+enum Slot {
+    /// 元ファイル上のバイト範囲。参照されたときにパースする
+    Disk { start: u64, end: u64 },
+    /// メモリ上の行。編集・追加された行はこちらになる
+    Mem(Vec<String>),
+}
+
+/// 遅延パースの読み出し元
+#[derive(Debug)]
+struct IndexedFile {
+    path: PathBuf,
+    dialect: Dialect,
+    /// ファイル上の列数。短い行はこの長さまで空文字で埋める
+    width: usize,
+    file: RefCell<Option<File>>,
+}
+impl IndexedFile {
+    /// 行を読み、ファイル上の列数までは空文字で、それより後に追加された列はfillで埋める
+    fn read_row(&self, start: u64, end: u64, width: usize, fill: &str) -> Result<Vec<String>> {
+        let mut file = self.file.borrow_mut();
+        if file.is_none() {
+            *file = Some(File::open(&self.path)?);
+        }
+        let f = file.as_mut().unwrap();
+        f.seek(SeekFrom::Start(start))?;
+        let mut buf = vec![0; (end - start) as usize];
+        f.read_exact(&mut buf)?;
+
+        let mut reader = self.dialect.reader_builder().from_reader(buf.as_slice());
+        let mut row: Vec<String> = match reader.records().next() {
+            Some(record) => record?.iter().map(String::from).collect(),
+            None => Vec::new(),
+        };
+        if row.len() < self.width {
+            row.resize(self.width, String::new());
+        }
+        row.resize(width, fill.to_string());
+        Ok(row)
+    }
+}
+
+/// テーブルの行データ
+/// 巨大なファイルはバイトオフセットの索引だけを持ち、表示・編集する行だけをパースする
+#[derive(Debug, Default)]
+pub struct RowStore {
+    slots: Vec<Slot>,
+    source: Option<IndexedFile>,
+    /// 行の列数
+    width: usize,
+    /// 追加した列を埋める値
+    fill: String,
+}
+impl RowStore {
+    /// メモリ上の行から作る
+    pub fn from_rows(rows: Vec<Vec<String>>) -> RowStore {
+        let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+        RowStore {
+            slots: rows
+                .into_iter()
+                .map(|mut r| {
+                    r.resize(width, String::new());
+                    Slot::Mem(r)
+                })
+                .collect(),
+            source: None,
+            width,
+            fill: String::new(),
+        }
+    }
+    /// ファイルを走査して行のバイトオフセットの索引を作る
+    /// 先頭行はヘッダとしてパースして返す
+    /// UTF-8として読めない行があればNone。行は後からUTF-8として読むので、全行をここで確かめておく
+    pub fn index(
+        path: &Path,
+        dialect: Dialect,
+    ) -> Result<Option<(Vec<String>, RowStore)>, LoadError> {
+        let file = File::open(path)?;
+        let mut reader = dialect
+            .reader_builder()
+            .from_reader(BufReader::with_capacity(1 << 20, file));
+
+        let mut record = ByteRecord::new();
+        let mut header: Vec<String> = Vec::new();
+        if reader.read_byte_record(&mut record)? {
+            if std::str::from_utf8(record.as_slice()).is_err() {
+                return Ok(None);
+            }
+            header = record
+                .iter()
+                .map(|cell| String::from_utf8_lossy(cell).into_owned())
+                .collect();
+            // BOMはヘッダの先頭セルに残るので取り除く
+            if let Some(first) = header.first_mut() {
+                if let Some(stripped) = first.strip_prefix('\u{feff}') {
+                    *first = stripped.to_string();
+                }
+            }
+        }
+
+        let mut width = header.len();
+        let mut slots = Vec::new();
+        while reader.read_byte_record(&mut record)? {
+            if std::str::from_utf8(record.as_slice()).is_err() {
+                return Ok(None);
+            }
+            let start = record.position().map(|p| p.byte()).unwrap_or(0);
+            let end = reader.position().byte();
+            width = std::cmp::max(width, record.len());
+            slots.push(Slot::Disk { start, end });
+        }
+        header.resize(width, String::new());
+
+        let source = IndexedFile {
+            path: path.to_path_buf(),
+            dialect,
+            width,
+            file: RefCell::new(None),
+        };
+        Ok(Some((
+            header,
+            RowStore {
+                slots,
+                source: Some(source),
+                width,
+                fill: String::new(),
+            },
+        )))
+    }
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+    /// 索引から遅延パースしているか
+    pub fn is_indexed(&self) -> bool {
+        self.source.is_some()
+    }
+    pub fn get(&self, index: usize) -> Result<Cow<'_, [String]>> {
+        match &self.slots[index] {
+            Slot::Mem(row) => Ok(Cow::Borrowed(row.as_slice())),
+            Slot::Disk { start, end } => {
+                let source = self.source.as_ref().unwrap();
+                Ok(Cow::Owned(
+                    source.read_row(*start, *end, self.width, &self.fill)?,
+                ))
+            }
+        }
+    }
+    /// 編集用に行をメモリ上に展開して返す
+    pub fn get_mut(&mut self, index: usize) -> Result<&mut Vec<String>> {
+        if let Slot::Disk { .. } = self.slots[index] {
+            let row = self.get(index)?.into_owned();
+            self.slots[index] = Slot::Mem(row);
+        }
+        match &mut self.slots[index] {
+            Slot::Mem(row) => Ok(row),
+            Slot::Disk { .. } => unreachable!(),
+        }
+    }
+    /// 索引の行をすべてメモリ上に読み込み、元ファイルを参照しないようにする
+    pub fn load_all(&mut self) -> Result<()> {
+        if self.source.is_none() {
+            return Ok(());
+        }
+        let rows = self
+            .iter()
+            .map(|row| row.map(Cow::into_owned))
+            .collect::<Result<Vec<_>>>()?;
+        self.slots = rows.into_iter().map(Slot::Mem).collect();
+        self.source = None;
+        Ok(())
+    }
+    /// 列数を変更し、増えた列をfillで埋める。索引の行は読み出し時に埋める
+    pub fn set_width(&mut self, width: usize, fill: &str) {
+        for slot in self.slots.iter_mut() {
+            if let Slot::Mem(row) = slot {
+                row.resize(width, fill.to_string());
+            }
+        }
+        self.width = width;
+        self.fill = fill.to_string();
+    }
+    pub fn insert(&mut self, index: usize, row: Vec<String>) {
+        self.slots.insert(index, Slot::Mem(row));
+    }
+    pub fn push(&mut self, row: Vec<String>) {
+        self.slots.push(Slot::Mem(row));
+    }
+    pub fn remove(&mut self, index: usize) {
+        self.slots.remove(index);
+    }
+    /// 全行を順に返す
+    /// 索引の行は1つのリーダーで先頭から順に読み、連続していない箇所だけシークする
+    pub fn iter(&self) -> impl Iterator<Item = Result<Cow<'_, [String]>>> {
+        let mut reader: Option<csv::Reader<BufReader<File>>> = None;
+        let mut pos = 0;
+        let mut record = csv::StringRecord::new();
+        self.slots.iter().map(move |slot| match slot {
+            Slot::Mem(row) => Ok(Cow::Borrowed(row.as_slice())),
+            Slot::Disk { start, end } => {
+                let source = self.source.as_ref().unwrap();
+                if reader.is_none() {
+                    let file = BufReader::with_capacity(1 << 20, File::open(&source.path)?);
+                    reader = Some(source.dialect.reader_builder().from_reader(file));
+                    pos = u64::MAX;
+                }
+                let r = reader.as_mut().unwrap();
+                if *start != pos {
+                    let mut position = csv::Position::new();
+                    position.set_byte(*start);
+                    r.seek_raw(SeekFrom::Start(*start), position)?;
+                }
+                r.read_record(&mut record)?;
+                pos = *end;
+                let mut row: Vec<String> = record.iter().map(String::from).collect();
+                if row.len() < source.width {
+                    row.resize(source.width, String::new());
+                }
+                row.resize(self.width, self.fill.clone());
+                Ok(Cow::Owned(row))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::SNIFF_BYTES;
+
+    /// ファイルに書き込んで索引を作る。索引の行は読み出し時にファイルを読むので、消すのは呼び出し側
+    fn index(name: &str, text: &str, dialect: Dialect) -> (PathBuf, Vec<String>, RowStore) {
+        let path = std::env::temp_dir().join(format!("crate-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let (header, rows) = RowStore::index(&path, dialect).unwrap().unwrap();
+        (path, header, rows)
+    }
+
+    fn rows(store: &RowStore) -> Vec<Vec<String>> {
+        store.iter().map(|row| row.unwrap().into_owned()).collect()
+    }
+
+    #[test]
+    fn reads_rows_at_their_offsets() {
+        let (path, header, store) = index(
+            "index.csv",
+            "\u{FEFF}id,memo\n1,plain\n2,\"multi\nline, quoted\"\n3\n",
+            Dialect::default(),
+        );
+        let got: Vec<Vec<String>> = (0..store.len())
+            .rev()
+            .map(|i| store.get(i).unwrap().into_owned())
+            .collect();
+        let all = rows(&store);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(header, ["id", "memo"]);
+        assert!(store.is_indexed());
+        assert_eq!(store.len(), 3);
+        assert_eq!(
+            got,
+            [
+                vec!["3".to_string(), String::new()],
+                vec!["2".to_string(), "multi\nline, quoted".to_string()],
+                vec!["1".to_string(), "plain".to_string()],
+            ]
+        );
+        assert_eq!(all, got.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reads_crlf_rows_with_another_delimiter() {
+        let dialect = Dialect {
+            delimiter: b';',
+            terminator: crate::dialect::Terminator::CrLf,
+            ..Dialect::default()
+        };
+        let (path, header, store) = index("crlf.csv", "a;b\r\n1;2\r\n3;4", dialect);
+        let all = rows(&store);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(header, ["a", "b"]);
+        assert_eq!(all, [["1", "2"], ["3", "4"]]);
+    }
+
+    #[test]
+    fn keeps_edited_rows_in_memory() {
+        let (path, _, mut store) = index("edit.csv", "a,b\n1,2\n3,4\n5,6\n", Dialect::default());
+        store.get_mut(1).unwrap()[1] = "edited".to_string();
+        store.remove(0);
+        store.push(vec!["7".to_string(), "8".to_string()]);
+        let all = rows(&store);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(all, [["3", "edited"], ["5", "6"], ["7", "8"]]);
+    }
+
+    #[test]
+    fn refuses_to_index_rows_that_are_not_utf8() {
+        let path = std::env::temp_dir().join(format!("crate-{}-sjis-tail.csv", std::process::id()));
+        let mut bytes = "a,b\n".repeat(SNIFF_BYTES).into_bytes();
+        bytes.extend_from_slice(&encoding_rs::SHIFT_JIS.encode("名前,値\n").0);
+        std::fs::write(&path, &bytes).unwrap();
+        let indexed = RowStore::index(&path, Dialect::default());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(indexed.unwrap().is_none());
+    }
+
+    #[test]
+    fn fills_added_columns_with_the_given_value() {
+        let (path, _, mut store) = index("widen.csv", "a,b\n1\n2,3\n", Dialect::default());
+        store.get_mut(1).unwrap();
+        store.set_width(3, "\\N");
+        let got = (store.get(0).unwrap().into_owned(), rows(&store));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(got.0, ["1", "", "\\N"]);
+        assert_eq!(got.1, [["1", "", "\\N"], ["2", "3", "\\N"]]);
+    }
+}
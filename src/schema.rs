@@ -0,0 +1,286 @@
+use crate::error::LoadError;
+use crate::model::{Column, DataType};
+use crate::prelude::*;
+use crate::validate::Rules;
+use regex::Regex;
+
+/// 宣言されたスキーマの1列
+/// 宣言された列は型推論の対象にならない
+#[derive(Debug, Clone)]
+pub struct ColumnSpec {
+    pub name: String,
+    pub data_type: DataType,
+    pub nullable: bool,
+    pub description: Option<String>,
+    pub rules: Rules,
+}
+impl ColumnSpec {
+    /// 現在の列の状態から作る。型が推論できていない列は文字列とする
+    pub fn from_column(column: &Column) -> ColumnSpec {
+        let data_type = match column.data_type {
+            DataType::Unknown => DataType::Utf8,
+            ref data_type => data_type.clone(),
+        };
+        ColumnSpec {
+            name: column.name.clone(),
+            data_type,
+            nullable: column.nullable,
+            description: column.description.clone(),
+            rules: column.rules.clone(),
+        }
+    }
+}
+
+/// テーブルのスキーマを宣言するファイルのパス。items.csv なら items.schema.toml
+pub fn sidecar_path(csv_path: &Path) -> PathBuf {
+    csv_path.with_extension("schema.toml")
+}
+
+/// 宣言されたスキーマを読む。ファイルがなければNone
+pub fn read_sidecar(csv_path: &Path) -> Result<Option<Vec<ColumnSpec>>, LoadError> {
+    let path = sidecar_path(csv_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let invalid = |reason: String| LoadError::Schema(path.clone(), reason);
+    let text = get_text(&path)?;
+    let document: toml::Table = text.parse().map_err(|e| invalid(format!("{}", e)))?;
+    let columns = match document.get("columns") {
+        Some(columns) => columns
+            .as_array()
+            .ok_or_else(|| invalid("columns must be an array of tables".to_string()))?,
+        None => return Ok(Some(Vec::new())),
+    };
+
+    let mut specs = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        let text = |key: &str| column.get(key).and_then(Value::as_str);
+        let name =
+            text("name").ok_or_else(|| invalid(format!("columns[{}]: name is missing", i)))?;
+        let mut data_type = text("type")
+            .ok_or_else(|| invalid(format!("{}: type is missing", name)))?
+            .parse::<DataType>()
+            .map_err(|e| invalid(format!("{}: {}", name, e)))?;
+        if let DataType::Enum(variants) = &mut data_type {
+            *variants = column
+                .get("values")
+                .and_then(Value::as_array)
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|v| v.as_str().map(String::from))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .filter(|values| !values.is_empty())
+                .ok_or_else(|| invalid(format!("{}: values must be a list of strings", name)))?;
+        }
+        // 数値の規則は整数でも小数でも書ける
+        let number = |key: &str| match column.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) => Ok(Some(*n as f64)),
+            Some(Value::Float(n)) => Ok(Some(*n)),
+            Some(_) => Err(invalid(format!("{}: {} must be a number", name, key))),
+        };
+        let length = |key: &str| match column.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if *n >= 0 => Ok(Some(*n as usize)),
+            Some(_) => Err(invalid(format!(
+                "{}: {} must be a non-negative integer",
+                name, key
+            ))),
+        };
+        let pattern = text("pattern")
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| invalid(format!("{}: pattern: {}", name, e)))?;
+        // required = true は nullable = false と同じ
+        let required = column
+            .get("required")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        specs.push(ColumnSpec {
+            name: name.to_string(),
+            data_type,
+            nullable: column
+                .get("nullable")
+                .and_then(Value::as_bool)
+                .unwrap_or(true)
+                && !required,
+            description: text("description").map(str::to_string),
+            rules: Rules {
+                unique: column
+                    .get("unique")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+                pattern,
+                min: number("min")?,
+                max: number("max")?,
+                min_length: length("min_length")?,
+                max_length: length("max_length")?,
+            },
+        });
+    }
+    Ok(Some(specs))
+}
+
+/// スキーマを宣言するファイルを書き、そのパスを返す
+pub fn write_sidecar(csv_path: &Path, specs: &[ColumnSpec]) -> Result<PathBuf> {
+    let columns = specs
+        .iter()
+        .map(|spec| {
+            let mut column = toml::Table::new();
+            column.insert("name".to_string(), Value::String(spec.name.clone()));
+            column.insert(
+                "type".to_string(),
+                Value::String(spec.data_type.to_string()),
+            );
+            if let DataType::Enum(variants) = &spec.data_type {
+                let values = variants.iter().cloned().map(Value::String).collect();
+                column.insert("values".to_string(), Value::Array(values));
+            }
+            column.insert("nullable".to_string(), Value::Boolean(spec.nullable));
+            if let Some(description) = &spec.description {
+                column.insert(
+                    "description".to_string(),
+                    Value::String(description.clone()),
+                );
+            }
+            let rules = &spec.rules;
+            if rules.unique {
+                column.insert("unique".to_string(), Value::Boolean(true));
+            }
+            if let Some(pattern) = &rules.pattern {
+                column.insert(
+                    "pattern".to_string(),
+                    Value::String(pattern.as_str().to_string()),
+                );
+            }
+            for (key, bound) in [("min", rules.min), ("max", rules.max)] {
+                // 整数で書かれた範囲は整数のまま書き戻す
+                let value = match bound {
+                    Some(bound) if bound.fract() == 0.0 => Value::Integer(bound as i64),
+                    Some(bound) => Value::Float(bound),
+                    None => continue,
+                };
+                column.insert(key.to_string(), value);
+            }
+            for (key, bound) in [
+                ("min_length", rules.min_length),
+                ("max_length", rules.max_length),
+            ] {
+                if let Some(bound) = bound {
+                    column.insert(key.to_string(), Value::Integer(bound as i64));
+                }
+            }
+            Value::Table(column)
+        })
+        .collect();
+    let mut document = toml::Table::new();
+    document.insert("columns".to_string(), Value::Array(columns));
+
+    let path = sidecar_path(csv_path);
+    save_to_file(toml::to_string(&document)?, path.clone())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_csv(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("crate-{}-{}.csv", std::process::id(), name))
+    }
+
+    #[test]
+    fn round_trips_sidecar() {
+        let csv_path = temp_csv("round-trip");
+        let specs = vec![
+            ColumnSpec {
+                name: "id".to_string(),
+                data_type: DataType::Int64,
+                nullable: false,
+                description: Some("識別子".to_string()),
+                rules: Rules {
+                    unique: true,
+                    min: Some(1.0),
+                    max: Some(2.5),
+                    ..Rules::default()
+                },
+            },
+            ColumnSpec {
+                name: "status".to_string(),
+                data_type: DataType::Enum(vec!["open".to_string(), "closed".to_string()]),
+                nullable: true,
+                description: None,
+                rules: Rules {
+                    pattern: Some(Regex::new("^[a-z]+$").unwrap()),
+                    min_length: Some(2),
+                    max_length: Some(8),
+                    ..Rules::default()
+                },
+            },
+        ];
+        let path = write_sidecar(&csv_path, &specs).unwrap();
+        let read = read_sidecar(&csv_path);
+        std::fs::remove_file(&path).unwrap();
+        let read = read.unwrap().unwrap();
+
+        assert_eq!(path, sidecar_path(&csv_path));
+        assert_eq!(read.len(), specs.len());
+        for (read, spec) in read.iter().zip(specs.iter()) {
+            assert_eq!(read.name, spec.name);
+            assert_eq!(read.data_type, spec.data_type);
+            assert_eq!(read.nullable, spec.nullable);
+            assert_eq!(read.description, spec.description);
+            assert_eq!(read.rules.unique, spec.rules.unique);
+            assert_eq!(
+                read.rules.pattern.as_ref().map(Regex::as_str),
+                spec.rules.pattern.as_ref().map(Regex::as_str)
+            );
+            assert_eq!(read.rules.min, spec.rules.min);
+            assert_eq!(read.rules.max, spec.rules.max);
+            assert_eq!(read.rules.min_length, spec.rules.min_length);
+            assert_eq!(read.rules.max_length, spec.rules.max_length);
+        }
+    }
+
+    #[test]
+    fn reads_required_as_not_nullable() {
+        let csv_path = temp_csv("required");
+        let path = sidecar_path(&csv_path);
+        std::fs::write(
+            &path,
+            "[[columns]]\nname = \"id\"\ntype = \"int\"\nrequired = true\n",
+        )
+        .unwrap();
+        let read = read_sidecar(&csv_path);
+        std::fs::remove_file(&path).unwrap();
+        let read = read.unwrap().unwrap();
+        assert!(!read[0].nullable);
+    }
+
+    #[test]
+    fn rejects_enum_without_values() {
+        let csv_path = temp_csv("enum");
+        let path = sidecar_path(&csv_path);
+        std::fs::write(&path, "[[columns]]\nname = \"s\"\ntype = \"enum\"\n").unwrap();
+        let read = read_sidecar(&csv_path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        let csv_path = temp_csv("unknown");
+        let path = sidecar_path(&csv_path);
+        std::fs::write(&path, "[[columns]]\nname = \"s\"\ntype = \"money\"\n").unwrap();
+        let read = read_sidecar(&csv_path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+
+    #[test]
+    fn has_no_sidecar_for_plain_csv() {
+        assert!(read_sidecar(&temp_csv("missing")).unwrap().is_none());
+    }
+}
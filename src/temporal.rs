@@ -0,0 +1,192 @@
+use crate::model::DataType;
+use crate::prelude::*;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{FixedOffset, NaiveDate, NaiveDateTime, NaiveTime};
+use std::fmt::Write;
+
+/// 日付として読む書式
+const DATE_FORMATS: [&str; 2] = ["%Y-%m-%d", "%Y/%m/%d"];
+/// オフセットのない日時として読む書式
+const DATETIME_FORMATS: [&str; 6] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+    "%Y/%m/%d %H:%M:%S%.f",
+    "%Y/%m/%d %H:%M",
+];
+/// オフセット付きの日時として読む書式。RFC 3339の形式はこれとは別に読む
+const DATETIME_TZ_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M:%S%.f%:z",
+    "%Y-%m-%d %H:%M:%S%.f %:z",
+    "%Y-%m-%dT%H:%M:%S%.f%z",
+    "%Y/%m/%d %H:%M:%S%.f %:z",
+];
+/// 時刻として読む書式
+const TIME_FORMATS: [&str; 2] = ["%H:%M:%S%.f", "%H:%M"];
+
+pub fn parse_date(value: &str) -> Option<NaiveDate> {
+    let value = value.trim();
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+}
+
+pub fn parse_datetime(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim();
+    DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+}
+
+pub fn parse_datetime_tz(value: &str) -> Option<DateTime<FixedOffset>> {
+    let value = value.trim();
+    DateTime::parse_from_rfc3339(value).ok().or_else(|| {
+        DATETIME_TZ_FORMATS
+            .iter()
+            .find_map(|format| DateTime::parse_from_str(value, format).ok())
+    })
+}
+
+pub fn parse_time(value: &str) -> Option<NaiveTime> {
+    let value = value.trim();
+    TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveTime::parse_from_str(value, format).ok())
+}
+
+/// 日付・時刻の型として読める値なら、その型を返す
+/// オフセット付きの日時、日時、日付、時刻の順に試す
+pub fn infer_temporal(value: &str) -> Option<DataType> {
+    if parse_datetime_tz(value).is_some() {
+        Some(DataType::DateTimeTz)
+    } else if parse_datetime(value).is_some() {
+        Some(DataType::DateTime)
+    } else if parse_date(value).is_some() {
+        Some(DataType::Date)
+    } else if parse_time(value).is_some() {
+        Some(DataType::Time)
+    } else {
+        None
+    }
+}
+
+/// 日付・時刻の列を表示するときの書式（chronoのstrftime形式）
+#[derive(Debug, Clone)]
+pub struct DisplayFormat {
+    pub date: String,
+    pub datetime: String,
+    pub datetime_tz: String,
+    pub time: String,
+}
+impl Default for DisplayFormat {
+    fn default() -> Self {
+        DisplayFormat {
+            date: "%Y-%m-%d".to_string(),
+            datetime: "%Y-%m-%d %H:%M:%S".to_string(),
+            datetime_tz: "%Y-%m-%d %H:%M:%S %:z".to_string(),
+            time: "%H:%M:%S".to_string(),
+        }
+    }
+}
+impl DisplayFormat {
+    /// 設定の[display]を読む。指定のない書式は既定のものを使う
+    pub fn from_config(config: &Value) -> Result<DisplayFormat> {
+        let display = config.get("display");
+        let get = |key: &str, default: String| -> Result<String> {
+            match display.and_then(|d| d.get(key)).and_then(Value::as_str) {
+                Some(format) => {
+                    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                        anyhow::bail!("display.{} is not a valid format: {}", key, format);
+                    }
+                    Ok(format.to_string())
+                }
+                None => Ok(default),
+            }
+        };
+        let default = DisplayFormat::default();
+        Ok(DisplayFormat {
+            date: get("date", default.date)?,
+            datetime: get("datetime", default.datetime)?,
+            datetime_tz: get("datetime_tz", default.datetime_tz)?,
+            time: get("time", default.time)?,
+        })
+    }
+    /// 列の型に合わせて値を表示用に整える。日付・時刻として読めない値はそのまま返す
+    pub fn format(&self, data_type: &DataType, value: &str) -> String {
+        let mut text = String::new();
+        let written = match data_type {
+            DataType::Date => parse_date(value).map(|v| write!(text, "{}", v.format(&self.date))),
+            DataType::DateTime => {
+                parse_datetime(value).map(|v| write!(text, "{}", v.format(&self.datetime)))
+            }
+            DataType::DateTimeTz => {
+                parse_datetime_tz(value).map(|v| write!(text, "{}", v.format(&self.datetime_tz)))
+            }
+            DataType::Time => parse_time(value).map(|v| write!(text, "{}", v.format(&self.time))),
+            _ => None,
+        };
+        match written {
+            Some(Ok(())) => text,
+            _ => value.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::DataTable;
+
+    #[test]
+    fn infers_temporal_types() {
+        assert_eq!(infer_temporal("2026-10-18"), Some(DataType::Date));
+        assert_eq!(infer_temporal("2026/10/18"), Some(DataType::Date));
+        assert_eq!(
+            infer_temporal("2026-10-18 09:30:00"),
+            Some(DataType::DateTime)
+        );
+        assert_eq!(infer_temporal("2026-10-18T09:30"), Some(DataType::DateTime));
+        assert_eq!(
+            infer_temporal("2026-10-18T09:30:00+09:00"),
+            Some(DataType::DateTimeTz)
+        );
+        assert_eq!(
+            infer_temporal("2026-10-18 09:30:00 +09:00"),
+            Some(DataType::DateTimeTz)
+        );
+        assert_eq!(infer_temporal("09:30"), Some(DataType::Time));
+        assert_eq!(infer_temporal("2026-13-01"), None);
+        assert_eq!(infer_temporal("tomorrow"), None);
+    }
+
+    #[test]
+    fn infers_temporal_columns() {
+        let data_table = DataTable::new(vec![
+            vec!["day", "at", "mixed"],
+            vec!["2026-10-17", "2026-10-17 08:00", "2026-10-17"],
+            vec!["2026-10-18", "2026-10-18", "09:00"],
+        ]);
+        let types: Vec<&DataType> = data_table
+            .schema
+            .columns
+            .iter()
+            .map(|c| &c.data_type)
+            .collect();
+        assert_eq!(
+            types,
+            [&DataType::Date, &DataType::DateTime, &DataType::Utf8]
+        );
+    }
+
+    #[test]
+    fn formats_only_readable_values() {
+        let display = DisplayFormat {
+            date: "%d/%m/%Y".to_string(),
+            ..DisplayFormat::default()
+        };
+        assert_eq!(display.format(&DataType::Date, "2026-10-18"), "18/10/2026");
+        assert_eq!(display.format(&DataType::Date, "soon"), "soon");
+        assert_eq!(display.format(&DataType::Utf8, "2026-10-18"), "2026-10-18");
+    }
+}
@@ -1,4 +1,6 @@
-use crate::model::{DataTable, StatefulList};
+use crate::diff::{Alignment as RowAlignment, RowChange, TableDiff, Version};
+use crate::model::{DataTable, StatefulList, TableState};
+use crate::temporal::DisplayFormat;
 use tui::{
     backend::Backend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
@@ -28,7 +30,13 @@ pub fn editor_title<'a>() -> Paragraph<'a> {
     )
 }
 
-pub fn edit<B: Backend>(f: &mut Frame<B>, data_table: &mut DataTable) {
+/// statusが空でなければ表の下に出す
+pub fn edit<B: Backend>(
+    f: &mut Frame<B>,
+    data_table: &mut DataTable,
+    display: &DisplayFormat,
+    status: &str,
+) {
     // 画面領域の分割
     let rects = Layout::default()
         .direction(Direction::Horizontal)
@@ -44,36 +52,93 @@ pub fn edit<B: Backend>(f: &mut Frame<B>, data_table: &mut DataTable) {
     let header_style = Style::default()
         .bg(Color::Black)
         .add_modifier(Modifier::BOLD);
+    // スキーマファイルで宣言された列は色を変える
     let value_headers = data_table.schema.columns.iter().map(|c| {
-        Cell::from(format!("{}\n [{}]", c.name, c.data_type))
-            .style(Style::default().fg(Color::Gray))
+        let color = if c.declared { Color::Cyan } else { Color::Gray };
+        Cell::from(format!("{}\n [{}]", c.name, c.data_type)).style(Style::default().fg(color))
     });
     let idx_header = [Cell::from("")].into_iter();
     let header_cells = idx_header.chain(value_headers);
     let header = Row::new(header_cells).style(header_style).height(2);
 
     //表示するデータの作成
-    let rows = data_table.values.iter().enumerate().map(|(index, item)| {
-        let height = item
+    // 巨大なテーブルでも表示範囲の行だけを読み出す
+    let banner_height = data_table.read_only.is_some() as u16;
+    let status_height = !status.is_empty() as u16;
+    let visible_height = rects[1]
+        .height
+        .saturating_sub(4 + banner_height + status_height) as usize;
+    let window = visible_window(data_table, visible_height);
+    let mut state = TableState::default();
+    state.select(
+        data_table
+            .state
+            .selected()
+            .map(|s| s.saturating_sub(data_table.offset)),
+    );
+
+    let null_style = Style::default()
+        .fg(Color::DarkGray)
+        .add_modifier(Modifier::ITALIC);
+    let rows = window.into_iter().map(|(index, item)| {
+        // NULLは空の文字列と区別して表示する
+        let is_null: Vec<bool> = item.iter().map(|v| data_table.nulls.is_null(v)).collect();
+        // 列の型やEnumの値の一覧に合わない値は目立たせる
+        let flagged: Vec<bool> = item
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                data_table
+                    .schema
+                    .columns
+                    .get(i)
+                    .is_some_and(|column| column.check(value, &data_table.nulls).is_some())
+            })
+            .collect();
+        // 日付・時刻の列は設定の表示形式で表示する
+        let item: Vec<String> = item
             .iter()
-            .map(|content| content.chars().filter(|c| *c == '\n').count())
-            .max()
-            .unwrap_or(0)
-            + 1;
+            .enumerate()
+            .map(|(i, value)| match data_table.schema.columns.get(i) {
+                _ if is_null[i] => "NULL".to_string(),
+                Some(column) => display.format(&column.data_type, value),
+                None => value.clone(),
+            })
+            .collect();
+        let height = row_height(&item);
 
         //9,999,999までindex可能
         let mut index_str = format!("{:>7}", index);
 
-        match data_table.rows_selected.contains(&index) {
-            true => {
-                index_str += "🎈";
-            }
-            false => {}
+        if data_table.rows_selected.contains(&index) {
+            index_str += "🎈";
         }
 
         let idx_cell =
             [Cell::from(index_str).style(Style::default().fg(Color::DarkGray))].into_iter();
-        let value_cells = item.iter().map(|c| Cell::from(c.clone()));
+        // 選択中の行では、選択中の列のセルを強調する
+        let selected_column = match data_table.state.selected() == Some(index) {
+            true => data_table.selected_column,
+            false => None,
+        };
+        let value_cells = item
+            .into_iter()
+            .zip(is_null.into_iter().zip(flagged))
+            .enumerate()
+            .map(|(i, (value, (is_null, flagged)))| {
+                let mut style = if is_null {
+                    null_style
+                } else {
+                    Style::default()
+                };
+                if flagged {
+                    style = style.fg(Color::Red);
+                }
+                if selected_column == Some(i) {
+                    style = style.add_modifier(Modifier::UNDERLINED | Modifier::BOLD);
+                }
+                Cell::from(value).style(style)
+            });
         let cells = idx_cell.chain(value_cells);
         Row::new(cells).height(height as u16).bottom_margin(0)
     });
@@ -101,14 +166,602 @@ pub fn edit<B: Backend>(f: &mut Frame<B>, data_table: &mut DataTable) {
 
     // 表示
     f.render_widget(title, rects[0]);
-    f.render_stateful_widget(t, rects[1], &mut data_table.state);
+    let panes = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(banner_height),
+                Constraint::Min(3),
+                Constraint::Length(status_height),
+            ]
+            .as_ref(),
+        )
+        .split(rects[1]);
+    // 読み取り専用のときは表の上にバナーを出す
+    if let Some(reason) = &data_table.read_only {
+        let banner = Paragraph::new(format!(" 読み取り専用: {}", reason))
+            .style(Style::default().fg(Color::Black).bg(Color::Yellow));
+        f.render_widget(banner, panes[0]);
+    }
+    f.render_stateful_widget(t, panes[1], &mut state);
+    f.render_widget(
+        Paragraph::new(status.to_string()).style(Style::default().fg(Color::Yellow)),
+        panes[2],
+    );
 }
 
-pub fn select<B: Backend>(f: &mut Frame<B>, menu_list: &mut StatefulList<ListItem>) {
-    // 画面領域の分割
+/// 行の表示上の高さ（セル内の改行数+1）
+fn row_height(row: &[String]) -> usize {
+    row.iter()
+        .map(|content| content.chars().filter(|c| *c == '\n').count())
+        .max()
+        .unwrap_or(0)
+        + 1
+}
+
+/// 選択行が収まるように表示の先頭行を調整し、表示範囲の行を返す
+fn visible_window(data_table: &mut DataTable, height: usize) -> Vec<(usize, Vec<String>)> {
+    let len = data_table.rows.len();
+    let read = |data_table: &DataTable, index: usize| match data_table.rows.get(index) {
+        Ok(row) => row.into_owned(),
+        Err(e) => vec![format!("<read error: {}>", e)],
+    };
+
+    if data_table.offset >= len {
+        data_table.offset = 0;
+    }
+    if let Some(selected) = data_table.state.selected() {
+        if selected < data_table.offset {
+            data_table.offset = selected;
+        }
+    }
+
+    let mut window = Vec::new();
+    let mut used = 0;
+    for index in data_table.offset..len {
+        let row = read(data_table, index);
+        used += row_height(&row);
+        if used > height && !window.is_empty() {
+            break;
+        }
+        window.push((index, row));
+    }
+
+    // 選択行が表示範囲より下にあれば、選択行を最下段にして組み直す
+    let last = window.last().map(|(i, _)| *i);
+    match (data_table.state.selected(), last) {
+        (Some(selected), Some(last)) if selected > last && selected < len => {
+            let mut window = Vec::new();
+            let mut used = 0;
+            for index in (0..=selected).rev() {
+                let row = read(data_table, index);
+                used += row_height(&row);
+                if used > height && !window.is_empty() {
+                    break;
+                }
+                window.push((index, row));
+            }
+            window.reverse();
+            data_table.offset = window.first().map(|(i, _)| *i).unwrap_or(0);
+            window
+        }
+        _ => window,
+    }
+}
+
+/// 親領域の中央に、指定割合の大きさの領域を作る
+pub fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
+    let popup_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_y) / 2),
+                Constraint::Percentage(percent_y),
+                Constraint::Percentage((100 - percent_y) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(r);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_x) / 2),
+                Constraint::Percentage(percent_x),
+                Constraint::Percentage((100 - percent_x) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(popup_layout[1])[1]
+}
+
+/// テーブル読み込み中の表示
+pub fn loading<B: Backend>(f: &mut Frame<B>, table_name: &str) {
+    let area = centered_rect(50, 20, f.size());
+    let text = Paragraph::new(format!("Loading {} ...", table_name))
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true })
+        .block(Block::default().borders(Borders::ALL).title("Loading"));
+    f.render_widget(Clear, area);
+    f.render_widget(text, area);
+}
+
+/// 確認ダイアログ
+pub fn confirm<B: Backend>(f: &mut Frame<B>, title: &str, lines: &[String], hint: &str) {
+    let area = centered_rect(60, 50, f.size());
+    let mut text: Vec<Spans> = lines.iter().map(|l| Spans::from(l.clone())).collect();
+    text.push(Spans::from(""));
+    text.push(Spans::from(Span::styled(
+        hint.to_string(),
+        Style::default().fg(Color::LightCyan),
+    )));
+    let dialog = Paragraph::new(text).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Yellow))
+            .title(title.to_string()),
+    );
+    f.render_widget(Clear, area);
+    f.render_widget(dialog, area);
+}
+
+/// 選択肢の一覧のダイアログ
+pub fn picker<B: Backend>(f: &mut Frame<B>, title: &str, list: &mut StatefulList<ListItem>) {
+    let area = centered_rect(40, 50, f.size());
+    let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+        .split(area);
+    let items = List::new(list.items.clone())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Yellow))
+                .title(title.to_string()),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(items, rects[0], &mut list.state);
+    f.render_widget(
+        Paragraph::new("↑↓: 選ぶ  Enter: 決定  Esc: キャンセル")
+            .style(Style::default().fg(Color::LightCyan)),
+        rects[1],
+    );
+}
+
+/// 1行の入力欄のダイアログ
+pub fn prompt<B: Backend>(f: &mut Frame<B>, textarea: &TextArea, hint: &str) {
+    let area = centered_rect(60, 20, f.size());
+    let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Length(1)].as_ref())
+        .split(area);
+    f.render_widget(Clear, area);
+    f.render_widget(textarea.widget(), rects[0]);
+    f.render_widget(
+        Paragraph::new(hint.to_string()).style(Style::default().fg(Color::LightCyan)),
+        rects[1],
+    );
+}
+
+/// 差分の表示に使う行数の上限
+const DIFF_LINES_LIMIT: usize = 1000;
+
+/// 差分を色付きの行に変換する
+pub fn diff_lines(
+    diff: &TableDiff,
+    old_header: &[String],
+    old_rows: &[Vec<String>],
+    new_header: &[String],
+    new_rows: &[Vec<String>],
+) -> Vec<Spans<'static>> {
+    let added = Style::default().fg(Color::Green);
+    let removed = Style::default().fg(Color::Red);
+    let changed = Style::default().fg(Color::Yellow);
+
+    let mut lines = Vec::new();
+    if diff.is_empty() {
+        lines.push(Spans::from("差分はありません"));
+        return lines;
+    }
+    if diff.header_changed {
+        lines.push(Spans::from(Span::styled(
+            format!("- header: {}", old_header.join(", ")),
+            removed,
+        )));
+        lines.push(Spans::from(Span::styled(
+            format!("+ header: {}", new_header.join(", ")),
+            added,
+        )));
+    }
+    for change in diff.changes.iter() {
+        if lines.len() >= DIFF_LINES_LIMIT {
+            lines.push(Spans::from("..."));
+            break;
+        }
+        match change {
+            RowChange::Added(new) => lines.push(Spans::from(Span::styled(
+                format!("+ [{}] {}", new, new_rows[*new].join(", ")),
+                added,
+            ))),
+            RowChange::Removed(old) => lines.push(Spans::from(Span::styled(
+                format!("- [{}] {}", old, old_rows[*old].join(", ")),
+                removed,
+            ))),
+            RowChange::Changed { old, new, cells } => {
+                lines.push(Spans::from(Span::styled(
+                    format!("~ [{} -> {}]", old, new),
+                    changed,
+                )));
+                for cell in cells {
+                    let column = new_header
+                        .get(*cell)
+                        .or_else(|| old_header.get(*cell))
+                        .cloned()
+                        .unwrap_or_default();
+                    let before = old_rows[*old].get(*cell).cloned().unwrap_or_default();
+                    let after = new_rows[*new].get(*cell).cloned().unwrap_or_default();
+                    lines.push(Spans::from(vec![
+                        Span::raw(format!("    {}: ", column)),
+                        Span::styled(before, removed),
+                        Span::raw(" -> "),
+                        Span::styled(after, added),
+                    ]));
+                }
+            }
+        }
+    }
+    lines
+}
+
+/// 差分画面に表示する行数の上限
+const DIFF_VIEW_LIMIT: usize = 10_000;
+
+/// 差分画面の表示形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffLayout {
+    Unified,
+    SideBySide,
+}
+
+/// 1行を、値の変わったセルを強調したSpanの列にする
+fn row_spans(
+    marker: &str,
+    index: Option<usize>,
+    row: &[String],
+    cells: &[usize],
+    style: Style,
+    cell_style: Style,
+) -> Spans<'static> {
+    let index = index.map(|i| i.to_string()).unwrap_or_default();
+    let mut spans = vec![Span::styled(format!("{} {:>7} ", marker, index), style)];
+    for (i, value) in row.iter().enumerate() {
+        if i > 0 {
+            spans.push(Span::styled(", ", style));
+        }
+        // 1行に収めるため、セル内の改行は記号で表す
+        let value = value.replace('\n', "⏎");
+        let style = match cells.contains(&i) {
+            true => cell_style,
+            false => style,
+        };
+        spans.push(Span::styled(value, style));
+    }
+    Spans::from(spans)
+}
+
+/// 差分画面の行を作る。左右に並べる場合は古い版と新しい版の行を返し、まとめる場合は右側は空になる
+pub fn diff_view_lines(
+    diff: &TableDiff,
+    old: &Version,
+    new: &Version,
+    layout: DiffLayout,
+    show_same: bool,
+) -> (Vec<Spans<'static>>, Vec<Spans<'static>>) {
+    let same = Style::default().fg(Color::Gray);
+    let added = Style::default().fg(Color::Green);
+    let removed = Style::default().fg(Color::Red);
+    let changed = Style::default().fg(Color::Yellow);
+    let added_cell = Style::default().fg(Color::Black).bg(Color::Green);
+    let removed_cell = Style::default().fg(Color::Black).bg(Color::Red);
+
+    let header_style = match diff.header_changed {
+        true => changed.add_modifier(Modifier::BOLD),
+        false => same.add_modifier(Modifier::BOLD),
+    };
+    let header_cells = crate::diff::changed_cells(&old.header, &new.header);
+    let mut left = vec![row_spans(
+        " ",
+        None,
+        &old.header,
+        &header_cells,
+        header_style,
+        removed_cell,
+    )];
+    let mut right = vec![row_spans(
+        " ",
+        None,
+        &new.header,
+        &header_cells,
+        header_style,
+        added_cell,
+    )];
+    if layout == DiffLayout::Unified {
+        left = match diff.header_changed {
+            true => vec![
+                row_spans("-", None, &old.header, &header_cells, removed, removed_cell),
+                row_spans("+", None, &new.header, &header_cells, added, added_cell),
+            ],
+            false => vec![row_spans(" ", None, &new.header, &[], header_style, same)],
+        };
+        right.clear();
+    }
+
+    for alignment in diff.alignments.iter() {
+        if left.len() >= DIFF_VIEW_LIMIT {
+            left.push(Spans::from("..."));
+            if layout == DiffLayout::SideBySide {
+                right.push(Spans::from("..."));
+            }
+            break;
+        }
+        match (*alignment, layout) {
+            (RowAlignment::Same(..), _) if !show_same => {}
+            (RowAlignment::Same(_, j), DiffLayout::Unified) => {
+                left.push(row_spans(" ", Some(j), &new.rows[j], &[], same, same))
+            }
+            (RowAlignment::Same(i, j), DiffLayout::SideBySide) => {
+                left.push(row_spans(" ", Some(i), &old.rows[i], &[], same, same));
+                right.push(row_spans(" ", Some(j), &new.rows[j], &[], same, same));
+            }
+            (RowAlignment::Removed(i), DiffLayout::Unified) => {
+                left.push(row_spans("-", Some(i), &old.rows[i], &[], removed, removed))
+            }
+            (RowAlignment::Removed(i), DiffLayout::SideBySide) => {
+                left.push(row_spans("-", Some(i), &old.rows[i], &[], removed, removed));
+                right.push(Spans::from(""));
+            }
+            (RowAlignment::Added(j), DiffLayout::Unified) => {
+                left.push(row_spans("+", Some(j), &new.rows[j], &[], added, added))
+            }
+            (RowAlignment::Added(j), DiffLayout::SideBySide) => {
+                left.push(Spans::from(""));
+                right.push(row_spans("+", Some(j), &new.rows[j], &[], added, added));
+            }
+            (RowAlignment::Changed(i, j), layout) => {
+                let cells = crate::diff::changed_cells(&old.rows[i], &new.rows[j]);
+                let before = row_spans("~", Some(i), &old.rows[i], &cells, changed, removed_cell);
+                let after = row_spans("~", Some(j), &new.rows[j], &cells, changed, added_cell);
+                match layout {
+                    DiffLayout::Unified => left.extend([before, after]),
+                    DiffLayout::SideBySide => {
+                        left.push(before);
+                        right.push(after);
+                    }
+                }
+            }
+        }
+    }
+    (left, right)
+}
+
+/// 2つの版の差分を表示する。右側の行が空ならまとめて表示する
+pub fn diff_view<B: Backend>(
+    f: &mut Frame<B>,
+    old: &Version,
+    new: &Version,
+    lines: &(Vec<Spans<'static>>, Vec<Spans<'static>>),
+    scroll: (u16, u16),
+    hint: &str,
+) {
+    let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+        .margin(2)
+        .split(f.size());
+    let block = |title: String| {
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::LightMagenta))
+            .title(title)
+    };
+
+    let (left, right) = lines;
+    match right.is_empty() {
+        true => {
+            let unified = Paragraph::new(left.clone())
+                .scroll(scroll)
+                .block(block(format!("{} → {}", old.label, new.label)));
+            f.render_widget(unified, rects[0]);
+        }
+        false => {
+            let panes = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+                .split(rects[0]);
+            let old_pane = Paragraph::new(left.clone())
+                .scroll(scroll)
+                .block(block(old.label.clone()));
+            let new_pane = Paragraph::new(right.clone())
+                .scroll(scroll)
+                .block(block(new.label.clone()));
+            f.render_widget(old_pane, panes[0]);
+            f.render_widget(new_pane, panes[1]);
+        }
+    }
+    f.render_widget(
+        Paragraph::new(hint.to_string()).style(Style::default().fg(Color::LightCyan)),
+        rects[1],
+    );
+}
+
+/// 復元候補の一覧と、選択中の候補のマスタとの差分
+pub fn recovery<B: Backend>(
+    f: &mut Frame<B>,
+    list: &mut StatefulList<ListItem>,
+    detail: &[Spans<'static>],
+) {
+    let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+        .margin(2)
+        .split(f.size());
+    let panes = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
+        .split(rects[0]);
+
+    let items = List::new(list.items.clone())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("前回の自動保存・退避データ"),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol("👉  ");
+    let detail = Paragraph::new(detail.to_vec())
+        .wrap(Wrap { trim: false })
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("マスタとの差分"),
+        );
+    let help = Paragraph::new("r: 復元  d: 破棄  Esc: 後で確認する")
+        .style(Style::default().fg(Color::LightCyan));
+
+    f.render_stateful_widget(items, panes[0], &mut list.state);
+    f.render_widget(detail, panes[1]);
+    f.render_widget(help, rects[1]);
+}
+
+/// 整合性チェックで見つかった、型や規則に合わないセルの一覧
+pub fn integrity<B: Backend>(f: &mut Frame<B>, list: &mut StatefulList<ListItem>, title: &str) {
+    let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+        .margin(2)
+        .split(f.size());
+
+    let items = List::new(list.items.clone())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(title.to_string()),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol("👉  ");
+    let help = Paragraph::new("Enter: セルに移動  r: 再検査  Esc: 戻る")
+        .style(Style::default().fg(Color::LightCyan));
+
+    f.render_stateful_widget(items, rects[0], &mut list.state);
+    f.render_widget(help, rects[1]);
+}
+
+/// 外部で変更されたテーブルの、ディスク上の内容と編集中の内容の差分
+pub fn conflict<B: Backend>(
+    f: &mut Frame<B>,
+    table_name: &str,
+    detail: &[Spans<'static>],
+    scroll: u16,
+    hint: &str,
+) {
     let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+        .margin(2)
+        .split(f.size());
+
+    let detail = Paragraph::new(detail.to_vec())
+        .wrap(Wrap { trim: false })
+        .scroll((scroll, 0))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Yellow))
+                .title(format!(
+                    "{} は他で変更されています（ディスク上の内容 → 編集中の内容）",
+                    table_name
+                )),
+        );
+    let help = Paragraph::new(hint.to_string()).style(Style::default().fg(Color::LightCyan));
+
+    f.render_widget(detail, rects[0]);
+    f.render_widget(help, rects[1]);
+}
+
+/// 履歴のスナップショットの一覧と、選択中のスナップショットに含まれるテーブル
+pub fn history<B: Backend>(
+    f: &mut Frame<B>,
+    snapshots: &mut StatefulList<ListItem>,
+    tables: &mut StatefulList<ListItem>,
+    focus_tables: bool,
+    status: &str,
+) {
+    let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Min(3),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
+        .margin(2)
+        .split(f.size());
+    let panes = Layout::default()
         .direction(Direction::Horizontal)
-        .constraints([Constraint::Min(50)].as_ref())
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(rects[0]);
+
+    // フォーカスのある側の枠を強調する
+    let border = |focused: bool| match focused {
+        true => Style::default().fg(Color::LightMagenta),
+        false => Style::default().fg(Color::DarkGray),
+    };
+    let snapshot_widget = List::new(snapshots.items.clone())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(border(!focus_tables))
+                .title("履歴（新しい順）"),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol("👉  ");
+    let table_widget = List::new(tables.items.clone())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(border(focus_tables))
+                .title("保存されたテーブル"),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol("👉  ");
+    let help = match focus_tables {
+        true => "Enter: 閲覧  d: 差分  r: このテーブルを復元  ←/Esc: 履歴に戻る",
+        false => {
+            "Enter/→: テーブルを選ぶ  m: 比較元に指定  r: このスナップショットを復元  Esc: 戻る"
+        }
+    };
+
+    f.render_stateful_widget(snapshot_widget, panes[0], &mut snapshots.state);
+    f.render_stateful_widget(table_widget, panes[1], &mut tables.state);
+    f.render_widget(
+        Paragraph::new(status.to_string()).style(Style::default().fg(Color::Yellow)),
+        rects[1],
+    );
+    f.render_widget(
+        Paragraph::new(help).style(Style::default().fg(Color::LightCyan)),
+        rects[2],
+    );
+}
+
+pub fn select<B: Backend>(f: &mut Frame<B>, menu_list: &mut StatefulList<ListItem>, status: &str) {
+    // 画面領域の分割
+    let rects = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
         .margin(5)
         .split(f.size());
 
@@ -119,4 +772,8 @@ pub fn select<B: Backend>(f: &mut Frame<B>, menu_list: &mut StatefulList<ListIte
         .highlight_symbol("👉  ");
     // 表示
     f.render_stateful_widget(items_widget, rects[0], &mut menu_list.state);
+    f.render_widget(
+        Paragraph::new(status.to_string()).style(Style::default().fg(Color::Yellow)),
+        rects[1],
+    );
 }
@@ -2,6 +2,9 @@ use crate::prelude::*;
 use std::ffi::OsString;
 use std::fs::{self, DirBuilder, File};
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
 
 /// 対象ディレクトリを再帰的に探索して、指定拡張子のファイルのパスの配列を返す
 pub fn glob(target: &str, target_ext: &str, recursive: bool) -> Result<Vec<OsString>> {
@@ -25,33 +28,231 @@ pub fn glob(target: &str, target_ext: &str, recursive: bool) -> Result<Vec<OsStr
 }
 
 /// 指定パスのファイルをStringに読み出して返す
-pub fn get_text(path: &Path) -> String {
-    let display = path.display();
+pub fn get_text(path: &Path) -> io::Result<String> {
     // 読み込み専用モード
-    let f = match File::open(&path) {
-        Err(e) => panic!("couldn't open {}: {}", display, &e.to_string()),
-        Ok(f) => f,
-    };
+    let f = File::open(path)?;
     // バッファリングされたストリーム
     let mut br = BufReader::new(f);
     let mut text = String::new();
-    if let Err(e) = br.read_to_string(&mut text) {
-        panic!("couldn't read {}: {}", display, &e.to_string())
+    br.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// 指定パスのファイルをバイト列で読み出して返す
+pub fn get_bytes(path: &Path) -> io::Result<Vec<u8>> {
+    // 読み込み専用モード
+    let f = File::open(path)?;
+    // バッファリングされたストリーム
+    let mut br = BufReader::new(f);
+    let mut bytes = Vec::new();
+    br.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// OSのユーザ名
+pub fn user_name() -> String {
+    whoami::username()
+}
+
+/// ホスト名。取得できなければ "unknown"
+pub fn host_name() -> String {
+    whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// バイト列のSHA-256ハッシュ（16進数）
+pub fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// ファイルの内容のSHA-256ハッシュ（16進数）
+pub fn file_hash(path: &Path) -> io::Result<String> {
+    let mut br = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = br.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// 読み込んだ時点のファイルの状態。外部で書き換えられたかの判定に使う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStamp {
+    pub modified: Option<SystemTime>,
+    pub len: u64,
+    /// 内容のハッシュ。書き込んだ内容が手元にあるときだけ持つ
+    pub hash: Option<String>,
+}
+impl FileStamp {
+    /// 更新日時と長さだけを取る。巨大なファイルでもすぐ済むよう、中身は読まない
+    pub fn read(path: &Path) -> io::Result<FileStamp> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: None,
+        })
+    }
+    /// 書き込んだ直後のファイルの状態。ハッシュは書き込んだ内容から取り、ファイルは読み直さない
+    pub fn written(path: &Path, content: &[u8]) -> io::Result<FileStamp> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: Some(content_hash(content)),
+        })
+    }
+    /// ファイルが記録した時点から変わっているか。消えていても変わったとみなす
+    /// 更新日時と長さが同じなら中身は読まない。違う場合、ハッシュがあれば中身を比べる
+    pub fn is_changed(&self, path: &Path) -> bool {
+        let Ok(metadata) = fs::metadata(path) else {
+            return true;
+        };
+        if metadata.modified().ok() == self.modified && metadata.len() == self.len {
+            return false;
+        }
+        match &self.hash {
+            Some(hash) => file_hash(path).map_or(true, |current| current != *hash),
+            None => true,
+        }
+    }
+}
+
+/// ファイルを安全に書き込む
+/// 同じディレクトリの一時ファイルに書き込んでfsyncしてから置き換えるので、
+/// 途中で落ちても元のファイルが中途半端な状態で残ることはない
+pub fn save_to_file(content: impl AsRef<[u8]>, path: PathBuf) -> Result<()> {
+    let tmp = write_temp(content.as_ref(), &path)?;
+    if let Err(e) = fs::rename(&tmp, &path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    sync_dir(&path);
+    Ok(())
+}
+
+/// 複数のファイルをまとめて置き換える
+/// いずれかの書き込みに失敗した場合は、置き換え済みのファイルも元に戻す
+pub fn save_files_atomically(files: &[(PathBuf, Vec<u8>)]) -> Result<()> {
+    // まずすべて一時ファイルに書き込む。失敗したら何も置き換えない
+    let mut temps = Vec::new();
+    for (path, content) in files.iter() {
+        match write_temp(content, path) {
+            Ok(tmp) => temps.push(tmp),
+            Err(e) => {
+                for tmp in temps.iter() {
+                    let _ = fs::remove_file(tmp);
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    // 元のファイルを退避してから置き換える
+    let mut committed: Vec<(&PathBuf, Option<PathBuf>)> = Vec::new();
+    let mut failure = None;
+    for ((path, _), tmp) in files.iter().zip(temps.iter()) {
+        let backup = match path.exists() {
+            true => {
+                let backup = sibling_path(path, "bak");
+                let _ = fs::remove_file(&backup);
+                if let Err(e) = back_up(path, &backup) {
+                    failure = Some(e);
+                    break;
+                }
+                Some(backup)
+            }
+            false => None,
+        };
+        if let Err(e) = fs::rename(tmp, path) {
+            if let Some(backup) = backup {
+                let _ = fs::remove_file(backup);
+            }
+            failure = Some(e);
+            break;
+        }
+        committed.push((path, backup));
+    }
+
+    match failure {
+        None => {
+            for (path, backup) in committed {
+                if let Some(backup) = backup {
+                    let _ = fs::remove_file(backup);
+                }
+                sync_dir(path);
+            }
+            Ok(())
+        }
+        Some(e) => {
+            // 置き換え済みのファイルを元に戻し、残りの一時ファイルを消す
+            for (path, backup) in committed.into_iter().rev() {
+                let _ = match backup {
+                    Some(backup) => fs::rename(backup, path),
+                    None => fs::remove_file(path),
+                };
+            }
+            for tmp in temps.iter().filter(|tmp| tmp.exists()) {
+                let _ = fs::remove_file(tmp);
+            }
+            Err(e.into())
+        }
     }
-    text
 }
 
-pub fn save_to_file(content: String, path: PathBuf) -> Result<()> {
+/// 置き換える前のファイルを退避する
+/// ハードリンクを作れないファイルシステム（SMBやFATの共有フォルダなど）ではコピーする
+fn back_up(path: &Path, backup: &Path) -> io::Result<()> {
+    if fs::hard_link(path, backup).is_ok() {
+        return Ok(());
+    }
+    fs::copy(path, backup).map(|_| ())
+}
+
+/// 対象ファイルと同じディレクトリにある作業用のファイル名
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}-{}", name, suffix, std::process::id()))
+}
+
+/// 対象ファイルと同じディレクトリに一時ファイルを書き込んでfsyncし、そのパスを返す
+fn write_temp(content: &[u8], path: &Path) -> Result<PathBuf> {
     let dir = path.parent().unwrap();
     // 指定ディレクトリが存在しない場合、作る
     if !&dir.exists() {
-        DirBuilder::new().recursive(true).create(&dir)?;
+        DirBuilder::new().recursive(true).create(dir)?;
+    }
+    let tmp = sibling_path(path, "tmp");
+    let res = File::create(&tmp).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()
+    });
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    // 置き換えても元のファイルの権限が変わらないようにする
+    // 権限を変えられないファイルシステムもあるので、失敗しても書き込みは続ける
+    if let Ok(metadata) = fs::metadata(path) {
+        let _ = fs::set_permissions(&tmp, metadata.permissions());
+    }
+    Ok(tmp)
+}
+
+/// ディレクトリのエントリの変更をディスクに反映する（対応していない環境では何もしない）
+fn sync_dir(path: &Path) {
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
     }
-    //write-onlyモードでファイルに書き込み
-    let mut file = File::create(path)?;
-    write!(file, "{}", content)?;
-    file.flush()?;
-    Ok(())
 }
 
 pub fn copy_recursive<U: AsRef<Path>, V: AsRef<Path>>(
@@ -103,3 +304,150 @@ pub fn copy_recursive<U: AsRef<Path>, V: AsRef<Path>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テストごとの空のディレクトリ
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crate-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> BTreeSet<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn replaces_every_file() {
+        let dir = temp_dir("replace");
+        let (a, b) = (dir.join("a.csv"), dir.join("b.csv"));
+        fs::write(&a, "old a").unwrap();
+        let result = save_files_atomically(&[
+            (a.clone(), b"new a".to_vec()),
+            (b.clone(), b"new b".to_vec()),
+        ]);
+        let contents = (fs::read_to_string(&a), fs::read_to_string(&b));
+        let left = entries(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(contents.0.unwrap(), "new a");
+        assert_eq!(contents.1.unwrap(), "new b");
+        assert_eq!(
+            left,
+            BTreeSet::from(["a.csv".to_string(), "b.csv".to_string()])
+        );
+    }
+
+    #[test]
+    fn rolls_back_replaced_files_when_a_later_one_fails() {
+        let dir = temp_dir("rollback");
+        let (a, b, c) = (dir.join("a.csv"), dir.join("b.csv"), dir.join("c.csv"));
+        fs::write(&a, "old a").unwrap();
+        // ディレクトリはファイルで置き換えられないので、2つ目の置き換えで失敗する
+        fs::create_dir(&b).unwrap();
+        fs::write(b.join("keep"), "").unwrap();
+        let result = save_files_atomically(&[
+            (a.clone(), b"new a".to_vec()),
+            (b.clone(), b"new b".to_vec()),
+            (c.clone(), b"new c".to_vec()),
+        ]);
+        let contents = fs::read_to_string(&a);
+        let left = entries(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(contents.unwrap(), "old a");
+        assert_eq!(
+            left,
+            BTreeSet::from(["a.csv".to_string(), "b.csv".to_string()])
+        );
+    }
+
+    #[test]
+    fn replaces_nothing_when_a_temp_file_fails() {
+        let dir = temp_dir("temp-fails");
+        let a = dir.join("a.csv");
+        fs::write(&a, "old a").unwrap();
+        // 親がファイルなので一時ファイルを作れない
+        fs::write(dir.join("file"), "").unwrap();
+        let result = save_files_atomically(&[
+            (a.clone(), b"new a".to_vec()),
+            (dir.join("file").join("b.csv"), b"new b".to_vec()),
+        ]);
+        let contents = fs::read_to_string(&a);
+        let left = entries(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(contents.unwrap(), "old a");
+        assert_eq!(
+            left,
+            BTreeSet::from(["a.csv".to_string(), "file".to_string()])
+        );
+    }
+
+    /// 長さを変えずに内容と更新日時を変える
+    fn rewrite_same_length(path: &Path, content: &str) {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, content).unwrap();
+        let later = modified + std::time::Duration::from_secs(5);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+    }
+
+    #[test]
+    fn stamp_notices_changed_and_removed_files() {
+        let dir = temp_dir("stamp");
+        let a = dir.join("a.csv");
+        fs::write(&a, "abc").unwrap();
+        let read = FileStamp::read(&a).unwrap();
+        let written = FileStamp::written(&a, b"abc").unwrap();
+        let untouched = (read.is_changed(&a), written.is_changed(&a));
+        rewrite_same_length(&a, "abc");
+        let touched = (read.is_changed(&a), written.is_changed(&a));
+        rewrite_same_length(&a, "xyz");
+        let same_length = written.is_changed(&a);
+        fs::write(&a, "abcd").unwrap();
+        let longer = written.is_changed(&a);
+        fs::remove_file(&a).unwrap();
+        let removed = (read.is_changed(&a), written.is_changed(&a));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(read.hash, None);
+        assert_eq!(untouched, (false, false));
+        // 読み込み時は内容を読まないので、更新日時が変われば変更とみなす
+        // 書き込んだ内容のハッシュがあれば、内容を比べる
+        assert_eq!(touched, (true, false));
+        assert!(same_length);
+        assert!(longer);
+        assert_eq!(removed, (true, true));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions_of_replaced_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("permissions");
+        let a = dir.join("a.csv");
+        fs::write(&a, "old a").unwrap();
+        fs::set_permissions(&a, fs::Permissions::from_mode(0o640)).unwrap();
+        let result = save_files_atomically(&[(a.clone(), b"new a".to_vec())]);
+        let mode = fs::metadata(&a).unwrap().permissions().mode();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(mode & 0o777, 0o640);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::model::DataTable;
+use crate::prelude::*;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// 列の値に課す規則。スキーマを宣言するファイルで列ごとに指定する
+/// NULLの値には適用しない
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    /// 列の中で値が重複してはならない
+    pub unique: bool,
+    /// 値が一致しなければならない正規表現。値全体に一致させるには ^ と $ を付ける
+    pub pattern: Option<Regex>,
+    /// 数値として読んだときの下限・上限
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// 文字数の下限・上限
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+}
+impl Rules {
+    /// 値が規則に合わなければ、その理由を返す。重複は行をまたぐので validate_table で調べる
+    pub fn check(&self, value: &str) -> Option<String> {
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(value) {
+                return Some(format!("\"{}\" が形式 {} に合いません", value, pattern));
+            }
+        }
+        if let Ok(number) = value.trim().parse::<f64>() {
+            if let Some(min) = self.min.filter(|min| number < *min) {
+                return Some(format!("{} は {} 以上でなければなりません", value, min));
+            }
+            if let Some(max) = self.max.filter(|max| number > *max) {
+                return Some(format!("{} は {} 以下でなければなりません", value, max));
+            }
+        }
+        let length = value.chars().count();
+        if let Some(min) = self.min_length.filter(|min| length < *min) {
+            return Some(format!(
+                "\"{}\" は {} 文字以上でなければなりません",
+                value, min
+            ));
+        }
+        if let Some(max) = self.max_length.filter(|max| length > *max) {
+            return Some(format!(
+                "\"{}\" は {} 文字以下でなければなりません",
+                value, max
+            ));
+        }
+        None
+    }
+}
+
+/// 型や規則に合わないセル1つ
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub row: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// テーブルの全行を、列の型・NULLの可否・規則で検査する。行、列の順に並べて返す
+pub fn validate_table(data_table: &DataTable) -> Result<Vec<Violation>> {
+    let columns = &data_table.schema.columns;
+    let mut violations = Vec::new();
+    // 重複を許さない列ごとの、値とそれが現れた行
+    let mut seen: Vec<HashMap<String, Vec<usize>>> = vec![HashMap::new(); columns.len()];
+
+    for (row, values) in data_table.rows.iter().enumerate() {
+        let values = values?;
+        for (column, spec) in columns.iter().enumerate() {
+            let value = values.get(column).map(String::as_str).unwrap_or_default();
+            if let Some(message) = spec.check(value, &data_table.nulls) {
+                violations.push(Violation {
+                    row,
+                    column,
+                    message,
+                });
+            }
+            if spec.rules.unique && !data_table.nulls.is_null(value) {
+                seen[column].entry(value.to_string()).or_default().push(row);
+            }
+        }
+    }
+
+    for (column, values) in seen.into_iter().enumerate() {
+        for (value, rows) in values {
+            if rows.len() < 2 {
+                continue;
+            }
+            for &row in &rows {
+                violations.push(Violation {
+                    row,
+                    column,
+                    message: format!("\"{}\" が{}行で重複しています", value, rows.len()),
+                });
+            }
+        }
+    }
+    violations.sort_by_key(|v| (v.row, v.column));
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::DataType;
+
+    fn found(data_table: &DataTable) -> Vec<(usize, usize)> {
+        validate_table(data_table)
+            .unwrap()
+            .iter()
+            .map(|v| (v.row, v.column))
+            .collect()
+    }
+
+    #[test]
+    fn checks_rules() {
+        let rules = Rules {
+            pattern: Some(Regex::new("^[A-Z]").unwrap()),
+            min: Some(0.0),
+            max: Some(10.0),
+            max_length: Some(3),
+            ..Rules::default()
+        };
+        assert!(rules.check("A1").is_none());
+        assert!(rules.check("a1").is_some());
+        assert!(rules.check("A").is_none());
+        assert!(rules.check("Abcd").is_some());
+
+        let range = Rules {
+            min: Some(0.0),
+            max: Some(10.0),
+            ..Rules::default()
+        };
+        assert!(range.check("10").is_none());
+        assert!(range.check("-1").is_some());
+        assert!(range.check("10.5").is_some());
+    }
+
+    #[test]
+    fn reports_duplicates_types_and_missing_values() {
+        let mut data_table = DataTable::new(vec![
+            vec!["id", "count", "memo"],
+            vec!["1", "5", "a"],
+            vec!["1", "x", "\\N"],
+            vec!["\\N", "7", "b"],
+            vec!["\\N", "8", "c"],
+        ]);
+        let columns = &mut data_table.schema.columns;
+        columns[0].rules.unique = true;
+        columns[1].data_type = DataType::Int64;
+        columns[1].declared = true;
+        columns[2].nullable = false;
+        assert_eq!(found(&data_table), [(0, 0), (1, 0), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn restricts_only_declared_enums() {
+        let mut data_table = DataTable::new(vec![
+            vec!["status"],
+            vec!["open"],
+            vec!["closed"],
+            vec!["other"],
+        ]);
+        data_table.schema.columns[0].data_type =
+            DataType::Enum(vec!["open".to_string(), "closed".to_string()]);
+        assert!(found(&data_table).is_empty());
+
+        data_table.schema.columns[0].declared = true;
+        assert_eq!(found(&data_table), [(2, 0)]);
+    }
+
+    #[test]
+    fn allows_nulls_in_inferred_columns() {
+        let mut data_table =
+            DataTable::new(vec![vec!["id", "name"], vec!["1", "a"], vec!["2", "b"]]);
+        data_table.add_row();
+        data_table.add_column("memo".to_string());
+        assert_eq!(data_table.rows.get(0).unwrap()[2], "\\N");
+        assert!(found(&data_table).is_empty());
+    }
+}